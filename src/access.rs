@@ -0,0 +1,166 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+use chrono::Utc;
+use futures::task::is_in_task;
+use futures::task_local;
+use log::info;
+use openssl::rand::rand_bytes;
+use serde_json::json;
+use warp::filters::log::{Info, Log};
+use warp::reply::WithHeader;
+use warp::{Filter, Rejection, Reply};
+
+/// The longest `X-Request-Id` taken from a client. Longer ones are replaced.
+const MAX_ID_LENGTH: usize = 200;
+
+/// How the request log is written.
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    /// With the other logs, as `warp::log` writes it.
+    Text,
+    /// To stdout as a line of JSON per request.
+    Json,
+}
+
+/// What the routes found out about the request being answered.
+#[derive(Debug, Default)]
+struct Request {
+    id: Option<String>,
+    client: Option<IpAddr>,
+    switch: Option<String>,
+    route: Option<&'static str>,
+}
+
+// The request being answered on this task. Connections are each their own task and answer one
+// request at a time.
+task_local! {
+    static REQUEST: RefCell<Request> = RefCell::new(Request::default())
+}
+
+/// Starts the request log entry of a new request from `client`.
+pub fn start(client: Option<IpAddr>) {
+    REQUEST.with(|r| {
+        *r.borrow_mut() = Request {
+            client,
+            ..Request::default()
+        }
+    });
+}
+
+/// Takes the request's `X-Request-Id`, or makes one up if it has none or it isn't printable ASCII,
+/// for `request_id()`. It goes just after `proxy::resolve`.
+pub fn id() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::header::optional::<String>("x-request-id")
+        .and_then(|id: Option<String>| {
+            let id = id
+                .filter(|id| {
+                    !id.is_empty()
+                        && id.len() <= MAX_ID_LENGTH
+                        && id.bytes().all(|b| b.is_ascii_graphic())
+                })
+                .unwrap_or_else(|| {
+                    let mut bytes = [0; 16];
+                    rand_bytes(&mut bytes).expect("the system has randomness");
+                    hex::encode(bytes)
+                });
+
+            REQUEST.with(|r| r.borrow_mut().id = Some(id));
+            Ok::<_, Rejection>(())
+        })
+        .untuple_one()
+}
+
+/// The id of the request being answered on this task, if there is one, so that what it leads to
+/// can be traced back to it.
+pub fn request_id() -> Option<String> {
+    if is_in_task() {
+        REQUEST.with(|r| r.borrow().id.clone())
+    } else {
+        None
+    }
+}
+
+/// `reply` with the `X-Request-Id` of the request, so the client can find it in the logs.
+pub fn with_id<T: Reply>(reply: T) -> WithHeader<T> {
+    warp::reply::with_header(reply, "x-request-id", request_id().unwrap_or_default())
+}
+
+/// Notes the switch, with its namespace, that the request is for.
+pub fn switch(name: &str) {
+    REQUEST.with(|r| r.borrow_mut().switch = Some(name.to_owned()));
+}
+
+/// Notes the route answering the request. It goes just before the handler so that only the route
+/// that takes the request does.
+pub fn route(name: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::any()
+        .and_then(move || {
+            REQUEST.with(|r| r.borrow_mut().route = Some(name));
+            Ok::<_, Rejection>(())
+        })
+        .untuple_one()
+}
+
+/// The route `route()` noted, if the request was taken by one.
+pub fn route_name() -> Option<&'static str> {
+    REQUEST.with(|r| r.borrow().route)
+}
+
+struct OrDash<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for OrDash<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(t) => t.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// Logs each request in `format`, from the client `proxy::resolve` found.
+pub fn log(name: &'static str, format: Format) -> Log<impl Fn(Info) + Copy> {
+    warp::log::custom(move |info: Info| {
+        REQUEST.with(|r| {
+            let request = r.borrow();
+
+            match format {
+                Format::Text => info!(
+                    target: name,
+                    "{} \"{} {} {:?}\" {} \"{}\" \"{}\" {:?} {}",
+                    OrDash(request.client),
+                    info.method(),
+                    info.path(),
+                    info.version(),
+                    info.status().as_u16(),
+                    OrDash(info.referer()),
+                    OrDash(info.user_agent()),
+                    info.elapsed(),
+                    OrDash(request.id.as_ref()),
+                ),
+                Format::Json => {
+                    let elapsed = info.elapsed();
+                    let line = json!({
+                        "at": Utc::now(),
+                        "request_id": request.id,
+                        "client": request.client,
+                        "method": info.method().as_str(),
+                        "path": info.path(),
+                        "status": info.status().as_u16(),
+                        "latency_ms": elapsed.as_secs() as f64 * 1e3
+                            + f64::from(elapsed.subsec_nanos()) / 1e6,
+                        "switch": request.switch,
+                        "route": request.route,
+                        "referer": info.referer(),
+                        "user_agent": info.user_agent(),
+                    });
+
+                    let stdout = io::stdout();
+                    let _ = writeln!(stdout.lock(), "{}", line);
+                }
+            }
+        })
+    })
+}
@@ -0,0 +1,156 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use warp::filters::path::FullPath;
+use warp::http::{Method, Response, StatusCode};
+use warp::{Filter, Rejection};
+
+use crate::access;
+use crate::auth::{self, Caller, Scope};
+use crate::proxy;
+
+/// How many entries `GET /admin/audit` answers with if it isn't asked for a `limit`.
+const DEFAULT_LIMIT: usize = 100;
+
+/// A call that changed something, as the audit log keeps it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    at: DateTime<Utc>,
+    /// The Basic user or bearer token tenant that made it, if there was one.
+    who: Option<String>,
+    method: String,
+    path: String,
+    /// The address it came from, if it is known.
+    addr: Option<String>,
+    request_id: Option<String>,
+}
+
+/// The query string of `GET /admin/audit`.
+#[derive(Deserialize)]
+pub struct Query {
+    /// Only the entries at or after this time.
+    since: Option<DateTime<Utc>>,
+    /// At most this many entries, the newest.
+    limit: Option<usize>,
+}
+
+/// Appends every call that changes something as a single line of JSON to a file. Unlike the event
+/// log it is never rotated, so that nothing is lost.
+pub struct AuditLog {
+    filename: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(filename: P) -> Self {
+        Self {
+            filename: filename.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn append(&self, entry: &Entry) -> Result<(), io::Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock();
+
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.filename)?
+            .write_all(&line)
+    }
+
+    /// The newest `limit` entries at or after `since`, newest first.
+    fn entries(&self, since: Option<DateTime<Utc>>, limit: usize) -> Result<Vec<Entry>, io::Error> {
+        let file = match File::open(&self.filename) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            file => file?,
+        };
+
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            let entry: Entry = serde_json::from_str(&line?)?;
+            if since.iter().all(|since| entry.at >= *since) {
+                entries.push(entry);
+            }
+        }
+
+        entries.reverse();
+        entries.truncate(limit);
+        Ok(entries)
+    }
+}
+
+/// Records the call in `log`, if there is one. It goes just before the handler of each route that
+/// changes something so that only the calls that route takes are recorded.
+pub fn record(log: Option<Arc<AuditLog>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(proxy::client())
+        .and(auth::caller())
+        .map(
+            move |method: Method, path: FullPath, addr: Option<IpAddr>, caller: Caller| {
+                let log = match &log {
+                    Some(log) => log,
+                    None => return,
+                };
+
+                let entry = Entry {
+                    at: Utc::now(),
+                    who: caller.0,
+                    method: method.to_string(),
+                    path: path.as_str().to_owned(),
+                    addr: addr.map(|addr| addr.to_string()),
+                    request_id: access::request_id(),
+                };
+
+                if let Err(e) = log.append(&entry) {
+                    warn!("failed to write audit log; {}", e);
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Answers `GET /admin/audit` with the entries asked for, or 404 without an audit log. Namespaced
+/// requests are refused as the log is of every switch.
+pub fn handle(
+    log: Option<Arc<AuditLog>>,
+    scope: Scope,
+    query: Query,
+) -> Result<Response<String>, Rejection> {
+    let mut res = Response::builder();
+
+    let log = match log {
+        _ if scope.is_namespaced() => {
+            res.status(StatusCode::FORBIDDEN);
+            return Ok(res.body(String::new()).unwrap());
+        }
+        None => {
+            res.status(StatusCode::NOT_FOUND);
+            return Ok(res.body(String::new()).unwrap());
+        }
+        Some(log) => log,
+    };
+
+    let entries = log
+        .entries(query.since, query.limit.unwrap_or(DEFAULT_LIMIT))
+        .map_err(|e| {
+            warn!("failed to read audit log; {}", e);
+            warp::reject::custom("Audit Log Error")
+        })?;
+    let body =
+        serde_json::to_string(&entries).map_err(|_| warp::reject::custom("Serialization Error"))?;
+
+    res.header("content-type", "application/json");
+    Ok(res.body(body).unwrap())
+}
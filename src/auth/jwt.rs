@@ -0,0 +1,351 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::future::{err, ok, Either};
+use futures::{Future, Stream};
+use log::{info, warn};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+use parking_lot::RwLock;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use tokio::timer::Interval;
+
+/// How far a token's `exp` and `nbf` may be off, for clocks that don't quite agree.
+const LEEWAY_SECS: i64 = 60;
+
+/// The `--jwt-*` options, from the command line or a `--config` file.
+#[derive(Clone, PartialEq)]
+pub struct Config {
+    pub secret: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwks_refresh: Duration,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub tenant_claim: String,
+}
+
+impl Config {
+    /// The validator for tokens, if there are keys to check them with.
+    pub fn validator(&self) -> Result<Option<Validator>, String> {
+        let keys = match (&self.secret, &self.jwks_url) {
+            (Some(secret), _) => Validator::secret(secret.as_bytes())?,
+            (None, Some(url)) => Keys::Jwks(url.clone()),
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Validator::new(
+            keys,
+            self.issuer.clone(),
+            self.audience.clone(),
+            self.tenant_claim.clone(),
+        )))
+    }
+}
+
+/// Where the keys tokens are signed with come from.
+pub enum Keys {
+    /// A secret shared with the issuer, for `HS256`, `HS384` and `HS512`.
+    Secret(PKey<Private>),
+    /// The issuer's public keys, fetched from this JWKS URL, for `RS256`, `RS384`, `RS512`,
+    /// `ES256` and `ES384`.
+    Jwks(String),
+}
+
+/// Checks bearer tokens and finds the tenant they are for.
+pub struct Validator {
+    keys: Keys,
+    /// The keys last fetched from the JWKS URL, by `kid`.
+    public_keys: RwLock<Vec<(Option<String>, PublicKey)>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    tenant_claim: String,
+}
+
+enum PublicKey {
+    Rsa(PKey<Public>),
+    Ec(EcKey<Public>, Nid),
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, String> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|e| format!("{}", e))
+}
+
+fn number(field: &Option<String>) -> Result<BigNum, String> {
+    let bytes = decode(field.as_ref().ok_or("missing a field")?)?;
+    BigNum::from_slice(&bytes).map_err(|e| format!("{}", e))
+}
+
+impl Jwk {
+    /// The key, or None if it is of a kind tokens can't be checked against.
+    fn public_key(&self) -> Result<Option<PublicKey>, String> {
+        if self.usage.iter().any(|usage| usage != "sig") {
+            return Ok(None);
+        }
+
+        match (self.kty.as_str(), &self.crv) {
+            ("RSA", _) => {
+                let rsa = Rsa::from_public_components(number(&self.n)?, number(&self.e)?)
+                    .map_err(|e| format!("{}", e))?;
+                let key = PKey::from_rsa(rsa).map_err(|e| format!("{}", e))?;
+                Ok(Some(PublicKey::Rsa(key)))
+            }
+            ("EC", Some(crv)) => {
+                let nid = match crv.as_str() {
+                    "P-256" => Nid::X9_62_PRIME256V1,
+                    "P-384" => Nid::SECP384R1,
+                    _ => return Ok(None),
+                };
+                let group = EcGroup::from_curve_name(nid).map_err(|e| format!("{}", e))?;
+                let (x, y) = (number(&self.x)?, number(&self.y)?);
+                let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+                    .map_err(|e| format!("{}", e))?;
+                Ok(Some(PublicKey::Ec(key, nid)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Validator {
+    pub fn new(
+        keys: Keys,
+        issuer: Option<String>,
+        audience: Option<String>,
+        tenant_claim: String,
+    ) -> Self {
+        Validator {
+            keys,
+            public_keys: RwLock::new(vec![]),
+            issuer,
+            audience,
+            tenant_claim,
+        }
+    }
+
+    pub fn secret(secret: &[u8]) -> Result<Keys, String> {
+        PKey::hmac(secret)
+            .map(Keys::Secret)
+            .map_err(|e| format!("{}", e))
+    }
+
+    /// Fetches the keys from the JWKS URL, if there is one, now and then every `every`, until the
+    /// validator is dropped. Until the first fetch succeeds tokens signed with them are refused.
+    pub fn refresh(
+        self: &Arc<Self>,
+        every: Duration,
+    ) -> Option<impl Future<Item = (), Error = ()>> {
+        let url = match &self.keys {
+            Keys::Jwks(url) => url.clone(),
+            Keys::Secret(_) => return None,
+        };
+        let client = reqwest::r#async::Client::new();
+        let validator: Weak<Self> = Arc::downgrade(self);
+
+        Some(
+            Interval::new(Instant::now(), every)
+                .map_err(|_| ())
+                .for_each(move |_| {
+                    // Replaced on reload, when its keys are no longer needed.
+                    let this = match validator.upgrade() {
+                        Some(this) => this,
+                        None => return Either::A(err(())),
+                    };
+                    let url = url.clone();
+
+                    Either::B(
+                        client
+                            .get(&url)
+                            .send()
+                            .and_then(|res| res.error_for_status())
+                            .and_then(|mut res| res.json::<JwkSet>())
+                            .map_err(|e| format!("{}", e))
+                            .map(move |set| {
+                                let keys: Vec<_> = set
+                                    .keys
+                                    .iter()
+                                    .filter_map(|jwk| match jwk.public_key() {
+                                        Ok(key) => key.map(|key| (jwk.kid.clone(), key)),
+                                        Err(e) => {
+                                            warn!(
+                                                "skipping invalid JWKS key; kid={:?}, {}",
+                                                jwk.kid, e
+                                            );
+                                            None
+                                        }
+                                    })
+                                    .collect();
+
+                                info!("fetched JWKS; keys={}", keys.len());
+                                *this.public_keys.write() = keys;
+                            })
+                            .or_else(move |e| {
+                                warn!("failed to fetch JWKS; url={}, {}", url, e);
+                                Ok(())
+                            }),
+                    )
+                })
+                .or_else(|_| ok(())),
+        )
+    }
+
+    fn verify(&self, header: &Header, signed: &[u8], signature: &[u8]) -> Result<(), String> {
+        let digest = match &header.alg[2..] {
+            "256" => MessageDigest::sha256(),
+            "384" => MessageDigest::sha384(),
+            "512" => MessageDigest::sha512(),
+            _ => return Err(format!("unsupported alg {}", header.alg)),
+        };
+
+        let valid = match (&self.keys, &header.alg[..2]) {
+            (Keys::Secret(secret), "HS") => {
+                let mut signer = Signer::new(digest, secret).map_err(|e| format!("{}", e))?;
+                signer.update(signed).map_err(|e| format!("{}", e))?;
+                let expected = signer.sign_to_vec().map_err(|e| format!("{}", e))?;
+
+                expected.len() == signature.len() && openssl::memcmp::eq(&expected, signature)
+            }
+            (Keys::Jwks(_), kind @ "RS") | (Keys::Jwks(_), kind @ "ES") => {
+                let keys = self.public_keys.read();
+                let key = keys
+                    .iter()
+                    .filter(|(kid, _)| header.kid.is_none() || kid == &header.kid)
+                    .map(|(_, key)| key)
+                    .find(|key| match key {
+                        PublicKey::Rsa(_) => kind == "RS",
+                        PublicKey::Ec(..) => kind == "ES",
+                    })
+                    .ok_or_else(|| format!("no key for kid {:?}", header.kid))?;
+
+                match key {
+                    PublicKey::Rsa(key) => {
+                        let mut verifier =
+                            Verifier::new(digest, key).map_err(|e| format!("{}", e))?;
+                        verifier.update(signed).map_err(|e| format!("{}", e))?;
+                        verifier.verify(signature).map_err(|e| format!("{}", e))?
+                    }
+                    PublicKey::Ec(key, nid) => {
+                        // ES256 is only ever P-256 and ES384 P-384. The signature is r and s
+                        // side by side, each as long as the curve.
+                        let len = match (nid, header.alg.as_str()) {
+                            (&Nid::X9_62_PRIME256V1, "ES256") => 32,
+                            (&Nid::SECP384R1, "ES384") => 48,
+                            _ => return Err(format!("{} doesn't match the key", header.alg)),
+                        };
+                        if signature.len() != 2 * len {
+                            return Err("malformed signature".to_owned());
+                        }
+
+                        let r =
+                            BigNum::from_slice(&signature[..len]).map_err(|e| format!("{}", e))?;
+                        let s =
+                            BigNum::from_slice(&signature[len..]).map_err(|e| format!("{}", e))?;
+                        let signature = EcdsaSig::from_private_components(r, s)
+                            .map_err(|e| format!("{}", e))?;
+                        let digest = hash(digest, signed).map_err(|e| format!("{}", e))?;
+
+                        signature
+                            .verify(&digest, key)
+                            .map_err(|e| format!("{}", e))?
+                    }
+                }
+            }
+            _ => return Err(format!("unexpected alg {}", header.alg)),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err("invalid signature".to_owned())
+        }
+    }
+
+    /// The tenant a valid `token` is for.
+    pub fn validate(&self, token: &str) -> Result<String, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err("not a JWT".to_owned());
+        }
+
+        let header: Header = serde_json::from_slice(&decode(parts[0])?)
+            .map_err(|e| format!("invalid header; {}", e))?;
+        if header.alg.len() != 5 || !header.alg.is_ascii() {
+            return Err(format!("unsupported alg {}", header.alg));
+        }
+
+        let signed_len = parts[0].len() + 1 + parts[1].len();
+        self.verify(&header, &token.as_bytes()[..signed_len], &decode(parts[2])?)?;
+
+        let claims: Value = serde_json::from_slice(&decode(parts[1])?)
+            .map_err(|e| format!("invalid claims; {}", e))?;
+        let now = Utc::now().timestamp();
+
+        match claims["exp"].as_i64() {
+            Some(exp) if exp + LEEWAY_SECS > now => {}
+            Some(_) => return Err("expired".to_owned()),
+            None => return Err("no exp".to_owned()),
+        }
+
+        if claims["nbf"]
+            .as_i64()
+            .iter()
+            .any(|nbf| nbf - LEEWAY_SECS > now)
+        {
+            return Err("not yet valid".to_owned());
+        }
+
+        if let Some(issuer) = &self.issuer {
+            if claims["iss"].as_str() != Some(issuer) {
+                return Err("wrong iss".to_owned());
+            }
+        }
+
+        if let Some(audience) = &self.audience {
+            let matches = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong aud".to_owned());
+            }
+        }
+
+        match claims[&self.tenant_claim].as_str() {
+            Some(tenant) if !tenant.is_empty() && !tenant.contains('/') => Ok(tenant.to_owned()),
+            Some(_) => Err(format!("invalid {} claim", self.tenant_claim)),
+            None => Err(format!("no {} claim", self.tenant_claim)),
+        }
+    }
+}
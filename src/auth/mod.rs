@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use futures::Future;
+use log::info;
+use parking_lot::RwLock;
+use warp::http::header::WWW_AUTHENTICATE;
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection};
+
+use crate::{access, config, Switch};
+
+pub mod jwt;
+
+/// The users allowed in with HTTP Basic auth, as name and password.
+#[derive(Debug, Default)]
+pub struct Users(HashMap<String, String>);
+
+/// Where the Basic auth users come from, so that they can be read again.
+#[derive(Debug, Clone, Default)]
+pub struct UsersConfig {
+    /// The `--basic-auth` pairs, which are only read at start.
+    pub pairs: Vec<String>,
+    /// A file of more, one `user:password` per line. It is read again on every load.
+    pub file: Option<String>,
+}
+
+impl UsersConfig {
+    /// The users as configured now.
+    pub fn load(&self) -> Result<Users, String> {
+        let mut users = Users::default();
+        for pair in &self.pairs {
+            users.add(pair)?;
+        }
+
+        if let Some(file) = &self.file {
+            let contents = std::fs::read_to_string(file).map_err(|e| format!("{}; {}", file, e))?;
+            users
+                .add_lines(&contents)
+                .map_err(|e| format!("{}; {}", file, e))?;
+        }
+
+        Ok(users)
+    }
+}
+
+/// Where the bearer tokens that are let in come from, so that they can be read again.
+pub struct TokensConfig {
+    /// The `--read-only-token`s, which are only read at start.
+    pub read_only: Vec<String>,
+    /// The `--jwt-*` options, which are only read at start.
+    pub jwt: jwt::Config,
+    /// A `--config` file, whose read-only tokens are let in as well and whose JWT options are
+    /// used instead of those of the command line. It is read again on every load.
+    pub config: Option<String>,
+}
+
+impl TokensConfig {
+    /// The read-only tokens and JWT options as configured now.
+    pub fn load(&self) -> Result<(Vec<String>, jwt::Config), String> {
+        let options = config::read(self.config.as_deref())?;
+        let value = |name, default: &Option<String>| {
+            options
+                .value_of(name)
+                .map(str::to_owned)
+                .or_else(|| default.clone())
+        };
+
+        let mut read_only = self.read_only.clone();
+        read_only.extend(options.values_of("read-only-token").map(str::to_owned));
+
+        // The keys are from the file or the command line, not a secret from one and a JWKS URL
+        // from the other.
+        let (secret, jwks_url) = match (
+            options.value_of("jwt-secret"),
+            options.value_of("jwt-jwks-url"),
+        ) {
+            (None, None) => (self.jwt.secret.clone(), self.jwt.jwks_url.clone()),
+            (secret, jwks_url) => (secret.map(str::to_owned), jwks_url.map(str::to_owned)),
+        };
+        let jwks_refresh = match options.value_of("jwt-jwks-refresh") {
+            Some(refresh) => humantime::parse_duration(refresh).map_err(|e| format!("{}", e))?,
+            None => self.jwt.jwks_refresh,
+        };
+
+        let jwt = jwt::Config {
+            secret,
+            jwks_url,
+            jwks_refresh,
+            issuer: value("jwt-issuer", &self.jwt.issuer),
+            audience: value("jwt-audience", &self.jwt.audience),
+            tenant_claim: options
+                .value_of("jwt-tenant-claim")
+                .map_or_else(|| self.jwt.tenant_claim.clone(), str::to_owned),
+        };
+
+        Ok((read_only, jwt))
+    }
+}
+
+/// The bearer tokens that are let in.
+pub struct Tokens {
+    /// Tokens for requests that may only read, such as those of dashboards.
+    read_only: Vec<String>,
+    /// The options `validator` was made with, so that it is only made again when they change.
+    jwt: jwt::Config,
+    validator: Option<Arc<jwt::Validator>>,
+}
+
+impl Tokens {
+    pub fn new(read_only: Vec<String>, jwt: jwt::Config) -> Result<Self, String> {
+        let validator = jwt.validator()?.map(Arc::new);
+        Ok(Tokens {
+            read_only,
+            jwt,
+            validator,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read_only.is_empty() && self.validator.is_none()
+    }
+
+    /// Fetches the keys of the validator, if it has a JWKS URL, for as long as it is used.
+    pub fn refresh(&self) -> Option<impl Future<Item = (), Error = ()>> {
+        self.validator
+            .as_ref()
+            .and_then(|validator| validator.refresh(self.jwt.jwks_refresh))
+    }
+}
+
+/// How requests prove who they are. With neither users, tokens nor a validator every request is
+/// let in.
+pub struct Auth {
+    pub users_config: UsersConfig,
+    pub users: RwLock<Users>,
+    pub tokens_config: TokensConfig,
+    pub tokens: RwLock<Tokens>,
+}
+
+/// What a request may do as far as its credentials say.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Full,
+    /// See switches but not change them, nor export them or read the audit log.
+    ReadOnly,
+}
+
+/// Whose switches a request may see and change. A request in a namespace, either the tenant of
+/// its bearer token or one under `/ns/:namespace`, only has the switches named `<namespace>/...`
+/// and knows them without the prefix. Everyone else has every switch.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    namespace: Option<String>,
+    /// Whether the namespace is the one the credentials are for and can't be left.
+    tenant: bool,
+}
+
+/// Who a request is from as far as its credentials say, the Basic user or the tenant of a bearer
+/// token. Nobody in particular if every request is let in.
+#[derive(Debug, Clone, Default)]
+pub struct Caller(pub Option<String>);
+
+/// Rejection of a request without the credentials `Auth` asks for.
+#[derive(Debug)]
+struct Unauthorized {
+    challenges: Vec<&'static str>,
+}
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unauthorized")
+    }
+}
+
+impl Error for Unauthorized {}
+
+/// Rejection of a tenant's request for a namespace other than its own, or of a read-only request
+/// for a route that isn't.
+#[derive(Debug)]
+struct Forbidden;
+
+impl fmt::Display for Forbidden {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("forbidden")
+    }
+}
+
+impl Error for Forbidden {}
+
+/// Splits `user:password` at the first colon, so passwords may have colons but names can't.
+pub fn parse_pair(pair: &str) -> Result<(&str, &str), String> {
+    match pair.find(':') {
+        Some(i) if i > 0 => Ok((&pair[..i], &pair[i + 1..])),
+        _ => Err("should be user:password".to_owned()),
+    }
+}
+
+/// The namespace a switch is in, from its name.
+pub fn namespace_of(name: &str) -> Option<&str> {
+    name.find('/').map(|i| &name[..i])
+}
+
+/// Whether `a` and `b` are equal, taking as long to find out however much of them is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Users {
+    pub fn add(&mut self, pair: &str) -> Result<(), String> {
+        let (user, password) = parse_pair(pair)?;
+        self.0.insert(user.to_owned(), password.to_owned());
+        Ok(())
+    }
+
+    /// Adds a `user:password` from each line of `contents`. Blank lines and lines starting with
+    /// `#` are skipped.
+    pub fn add_lines(&mut self, contents: &str) -> Result<(), String> {
+        contents
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .try_for_each(|(i, line)| self.add(line).map_err(|e| format!("line {}; {}", i + 1, e)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The user whose base64 `credentials` from a Basic `Authorization` header these are, if any.
+    fn user(&self, credentials: &str) -> Option<String> {
+        let decoded = match base64::decode(credentials).ok().map(String::from_utf8) {
+            Some(Ok(decoded)) => decoded,
+            _ => return None,
+        };
+
+        let (user, password) = parse_pair(&decoded).ok()?;
+        let expected = self.0.get(user)?;
+
+        if constant_time_eq(expected.as_bytes(), password.as_bytes()) {
+            Some(user.to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+impl Auth {
+    /// Loads the Basic auth users and bearer tokens again and lets in those from now on. The JWT
+    /// validator is only made again if its options changed, so that the keys fetched from a JWKS
+    /// URL are kept. If they can't be loaded, or would leave nothing to check where there was,
+    /// letting every request in, the ones there were are kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let users = self.users_config.load()?;
+        let (read_only, jwt) = self.tokens_config.load()?;
+
+        let changed = jwt != self.tokens.read().jwt;
+        let tokens = if changed {
+            Tokens::new(read_only, jwt)?
+        } else {
+            let current = self.tokens.read();
+            Tokens {
+                read_only,
+                jwt,
+                validator: current.validator.clone(),
+            }
+        };
+
+        let mut current_users = self.users.write();
+        let mut current_tokens = self.tokens.write();
+        if users.is_empty()
+            && tokens.is_empty()
+            && !(current_users.is_empty() && current_tokens.is_empty())
+        {
+            return Err("no users or tokens left, which would let every request in".to_owned());
+        }
+
+        if changed {
+            if let Some(refresh) = tokens.refresh() {
+                tokio::spawn(refresh);
+            }
+        }
+        *current_users = users;
+        *current_tokens = tokens;
+        info!("auth reloaded; jwt_changed={}", changed);
+        Ok(())
+    }
+
+    fn challenges(&self) -> Vec<&'static str> {
+        let mut challenges = vec![];
+        if !self.users.read().is_empty() {
+            challenges.push("Basic realm=\"condemn\"");
+        }
+        if !self.tokens.read().is_empty() {
+            challenges.push("Bearer realm=\"condemn\"");
+        }
+        challenges
+    }
+
+    /// The scope, caller and access of a request with this `Authorization` header, if it is let
+    /// in.
+    fn check(&self, authorization: Option<String>) -> Option<(Scope, Caller, Access)> {
+        let users = self.users.read();
+        let tokens = self.tokens.read();
+        if users.is_empty() && tokens.is_empty() {
+            return Some((Scope::default(), Caller::default(), Access::Full));
+        }
+
+        let authorization = authorization?;
+        let (scheme, credentials) = match authorization.find(' ') {
+            Some(i) => (&authorization[..i], authorization[i + 1..].trim()),
+            None => return None,
+        };
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            if let Some(user) = users.user(credentials) {
+                return Some((Scope::default(), Caller(Some(user)), Access::Full));
+            }
+        }
+
+        if scheme.eq_ignore_ascii_case("bearer")
+            && tokens
+                .read_only
+                .iter()
+                .any(|token| constant_time_eq(token.as_bytes(), credentials.as_bytes()))
+        {
+            return Some((Scope::default(), Caller::default(), Access::ReadOnly));
+        }
+
+        match &tokens.validator {
+            Some(jwt) if scheme.eq_ignore_ascii_case("bearer") => match jwt.validate(credentials) {
+                Ok(tenant) => Some((
+                    Scope {
+                        namespace: Some(tenant.clone()),
+                        tenant: true,
+                    },
+                    Caller(Some(tenant)),
+                    Access::Full,
+                )),
+                Err(e) => {
+                    info!("refused bearer token; {}", e);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Scope {
+    /// Whether the request is kept to a namespace's switches.
+    pub fn is_namespaced(&self) -> bool {
+        self.namespace.is_some()
+    }
+
+    /// The scope for `/ns/:namespace`, or None if the request may not go there. A tenant may
+    /// only name its own.
+    fn within(&self, namespace: String) -> Option<Scope> {
+        match &self.namespace {
+            Some(own) if self.tenant && own != &namespace => None,
+            Some(_) if self.tenant => Some(self.clone()),
+            _ => Some(Scope {
+                namespace: Some(namespace),
+                tenant: false,
+            }),
+        }
+    }
+
+    /// The name of the switch the request calls `name`.
+    pub fn name(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name.to_owned(),
+        }
+    }
+
+    /// The name the request knows the switch `name` by, or None if it isn't one of its switches.
+    pub fn local_name<'a>(&self, name: &'a str) -> Option<&'a str> {
+        match &self.namespace {
+            Some(namespace) if namespace_of(name) == Some(namespace) => {
+                Some(&name[namespace.len() + 1..])
+            }
+            Some(_) => None,
+            None => Some(name),
+        }
+    }
+
+    /// `s` as the request knows it, or None if it isn't one of its switches.
+    pub fn local(&self, mut s: Switch) -> Option<Switch> {
+        s.name = self.local_name(&s.name)?.to_owned();
+        Some(s)
+    }
+}
+
+/// Lets in requests with the credentials `auth` asks for and gives the rest to `recover` to be
+/// answered with 401. Their `Scope` is left for `namespace()`, their `Caller` for `caller()` and
+/// their `Access` for `write()`.
+pub fn authenticate(auth: Arc<Auth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(
+            move |authorization: Option<String>| match auth.check(authorization) {
+                Some((scope, caller, access)) => {
+                    warp::ext::set(scope);
+                    warp::ext::set(caller);
+                    warp::ext::set(access);
+                    Ok(())
+                }
+                None => Err(warp::reject::custom(Unauthorized {
+                    challenges: auth.challenges(),
+                })),
+            },
+        )
+        .untuple_one()
+}
+
+/// Takes a `/ns/:namespace` prefix, if there is one, and puts the request's `Scope` in the
+/// namespace for `scope()` and `name()` of the routes after. Tenants asking for another are given
+/// to `recover` to be answered with 403.
+pub fn namespace() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::path("ns")
+        .and(warp::path::param())
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(scope())
+        .and_then(|namespace: Option<String>, scope: Scope| {
+            match namespace.map(|namespace| scope.within(namespace)) {
+                Some(Some(scope)) => warp::ext::set(scope),
+                Some(None) => return Err(warp::reject::custom(Forbidden)),
+                None => {}
+            }
+            Ok(())
+        })
+        .untuple_one()
+}
+
+/// The `Scope` `authenticate` and `namespace` found for the request.
+pub fn scope() -> impl Filter<Extract = (Scope,), Error = Rejection> + Copy {
+    warp::ext::get::<Scope>()
+}
+
+/// The `Caller` `authenticate` found for the request.
+pub fn caller() -> impl Filter<Extract = (Caller,), Error = Rejection> + Copy {
+    warp::ext::get::<Caller>()
+}
+
+/// Gives requests with read-only access to `recover` to be answered with 403. It goes on every
+/// route that changes switches, just before `audit::record`, and those that aren't for dashboards.
+pub fn write() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::ext::get::<Access>()
+        .and_then(|access: Access| match access {
+            Access::Full => Ok(()),
+            Access::ReadOnly => Err(warp::reject::custom(Forbidden)),
+        })
+        .untuple_one()
+}
+
+/// The switch name path segment, within the request's scope. It is noted for the request log.
+pub fn name() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+    scope()
+        .and(warp::path::param())
+        .map(|scope: Scope, name: String| {
+            let name = scope.name(&name);
+            access::switch(&name);
+            name
+        })
+}
+
+/// Answers the rejections of `authenticate` with a challenge for the client's credentials and
+/// those of `namespace` and `write` with 403.
+pub fn recover(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    if rejection.find_cause::<Forbidden>().is_some() {
+        let mut res = Response::builder();
+        res.status(StatusCode::FORBIDDEN);
+        return Ok(res.body(String::new()).expect("the response is valid"));
+    }
+
+    let challenges = match rejection.find_cause::<Unauthorized>() {
+        Some(unauthorized) => &unauthorized.challenges,
+        None => return Err(rejection),
+    };
+
+    let mut res = Response::builder();
+    res.status(StatusCode::UNAUTHORIZED);
+    for challenge in challenges {
+        res.header(WWW_AUTHENTICATE, *challenge);
+    }
+
+    Ok(res.body(String::new()).expect("the response is valid"))
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::future::{err, ok};
+use futures::{Future, Stream};
+use log::{info, warn};
+
+use crate::stores::disk::write_file;
+use crate::stores::Store;
+use crate::Switch;
+
+/// Snapshots every switch in a store to `<dir>/condemn-<timestamp>.json`, a JSON array of
+/// switches, keeping the newest `keep` of them. Works the same whatever the store's backend.
+#[derive(Debug, Clone)]
+pub struct Backups {
+    dir: PathBuf,
+    keep: usize,
+}
+
+impl Backups {
+    pub fn new<P: AsRef<Path>>(dir: P, keep: usize) -> Self {
+        Backups {
+            dir: dir.as_ref().to_path_buf(),
+            keep,
+        }
+    }
+
+    pub fn snapshot<S: Store>(&self, store: &S) -> impl Future<Item = (), Error = ()> {
+        let this = self.clone();
+
+        store.all().and_then(move |switches| {
+            let filename = this
+                .dir
+                .join(Utc::now().format("condemn-%Y%m%dT%H%M%SZ.json").to_string());
+
+            let json = serde_json::to_vec(&switches)
+                .map_err(|e| warn!("failed to serialize backup; {}", e))?;
+
+            fs::create_dir_all(&this.dir)
+                .and_then(|_| write_file(&filename, &json))
+                .map_err(|e| warn!("failed to write backup '{:?}'; {}", filename, e))?;
+
+            info!("wrote backup '{:?}'; switches={}", filename, switches.len());
+
+            this.rotate();
+
+            Ok(())
+        })
+    }
+
+    /// Removes all but the newest `keep` backups. The timestamp in the name sorts by age.
+    fn rotate(&self) {
+        let mut backups: Vec<PathBuf> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(
+                    |path| match path.file_name().and_then(|name| name.to_str()) {
+                        Some(name) => name.starts_with("condemn-") && name.ends_with(".json"),
+                        None => false,
+                    },
+                )
+                .collect(),
+            Err(e) => {
+                warn!("failed to list backups in '{:?}'; {}", self.dir, e);
+                return;
+            }
+        };
+
+        backups.sort();
+
+        let remove = backups.len().saturating_sub(self.keep);
+        for old in backups.drain(..remove) {
+            match fs::remove_file(&old) {
+                Ok(_) => info!("removed old backup '{:?}'", old),
+                Err(e) => warn!("failed to remove old backup '{:?}'; {}", old, e),
+            }
+        }
+    }
+}
+
+/// Loads a backup written by `Backups` into `store`. Switches in the backup replace those of the
+/// same name; any others in the store are left alone.
+pub fn restore<S: 'static + Store + Send + Sync>(
+    store: Arc<S>,
+    filename: &str,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let switches: Vec<Switch> = match fs::read(filename)
+        .map_err(|e| format!("{}", e))
+        .and_then(|data| serde_json::from_slice(&data).map_err(|e| format!("{}", e)))
+    {
+        Ok(switches) => switches,
+        Err(e) => {
+            warn!("failed to read backup '{}'; {}", filename, e);
+            return Box::new(err(()));
+        }
+    };
+
+    let count = switches.len();
+    let filename = filename.to_owned();
+    let insert_store = Arc::clone(&store);
+
+    Box::new(store.init().and_then(move |_| {
+        futures::stream::iter_ok(switches)
+            .for_each(move |s| insert_store.insert(s))
+            .and_then(move |_| {
+                info!("restored '{}'; switches={}", filename, count);
+                ok(())
+            })
+    }))
+}
+
+/// Copies every switch in `from` to `to`, replacing any of the same name. `from` is left as it
+/// was.
+pub fn migrate<F, T>(from: F, to: T) -> Box<Future<Item = (), Error = ()> + Send>
+where
+    F: 'static + Store + Send + Sync,
+    T: 'static + Store + Send + Sync,
+{
+    let to = Arc::new(to);
+    let init_to = Arc::clone(&to);
+
+    Box::new(
+        from.init()
+            .and_then(move |_| init_to.init())
+            .and_then(move |_| from.all())
+            .and_then(move |switches| {
+                let count = switches.len();
+
+                futures::stream::iter_ok(switches)
+                    .for_each(move |s| {
+                        let name = s.name.clone();
+                        to.update(&name, Arc::new(move |_| Some(s.clone())))
+                            .map(|_| ())
+                    })
+                    .map(move |_| info!("migrated; switches={}", count))
+            }),
+    )
+}
@@ -0,0 +1,116 @@
+use futures::{Future, Stream};
+use log::{error, info};
+use reqwest::r#async::{Client as HttpClient, RequestBuilder};
+use reqwest::Method;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use crate::auth;
+
+/// Changes switches on a condemn server from the command line, so that cron jobs and people
+/// needn't write out the requests with curl.
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: HttpClient,
+    server: String,
+    /// The switch's token, sent as `X-Switch-Token`.
+    token: Option<String>,
+    /// `user:password` for Basic auth.
+    user: Option<String>,
+}
+
+/// What to do to a switch.
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Check in, re-arming it with `deadline` and `window` when given as it would be by `PUT`.
+    Checkin {
+        deadline: Option<String>,
+        window: Option<String>,
+    },
+    Delete,
+    /// Pause its countdown until it is resumed.
+    Snooze,
+    Resume,
+}
+
+impl Client {
+    pub fn new(server: &str, token: Option<String>, user: Option<String>) -> Self {
+        Client {
+            client: HttpClient::new(),
+            server: server.trim_end_matches('/').to_owned(),
+            token,
+            user,
+        }
+    }
+
+    fn request(&self, method: Method, name: &str, action: Option<&str>) -> RequestBuilder {
+        let mut url = format!(
+            "{}/{}",
+            self.server,
+            utf8_percent_encode(name, PATH_SEGMENT_ENCODE_SET)
+        );
+        if let Some(action) = action {
+            url.push('/');
+            url.push_str(action);
+        }
+
+        let mut request = self.client.request(method, &url);
+        if let Some(token) = &self.token {
+            request = request.header("X-Switch-Token", token.as_str());
+        }
+        if let Some((user, password)) = self.user.as_ref().and_then(|u| auth::parse_pair(u).ok()) {
+            request = request.basic_auth(user, Some(password));
+        }
+
+        request
+    }
+
+    /// Does `command` to the switch called `name`, erring with why if the server didn't.
+    pub fn run(&self, name: &str, command: Command) -> impl Future<Item = (), Error = ()> {
+        let request = match &command {
+            Command::Checkin {
+                deadline: Some(deadline),
+                window,
+            } => {
+                let mut query = vec![("deadline", deadline.as_str())];
+                query.extend(window.as_ref().map(|window| ("window", window.as_str())));
+                self.request(Method::PUT, name, None).query(&query)
+            }
+            Command::Checkin { deadline: None, .. } => {
+                self.request(Method::POST, name, Some("checkin"))
+            }
+            Command::Delete => self.request(Method::DELETE, name, None),
+            Command::Snooze => self.request(Method::POST, name, Some("pause")),
+            Command::Resume => self.request(Method::POST, name, Some("resume")),
+        };
+
+        let name = name.to_owned();
+        request
+            .send()
+            .map_err(|e| error!("request failed; {}", e))
+            .and_then(move |res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map_err(|e| error!("failed to read the answer; {}", e))
+                    .and_then(move |body| {
+                        let body = String::from_utf8_lossy(&body);
+                        if status.is_success() {
+                            info!(
+                                "done; name={}, command={:?}, status={}",
+                                name, command, status
+                            );
+                            Ok(())
+                        } else {
+                            error!(
+                                "refused; name={}, command={:?}, status={}, body={}",
+                                name,
+                                command,
+                                status,
+                                body.trim()
+                            );
+                            Err(())
+                        }
+                    })
+            })
+    }
+}
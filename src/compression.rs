@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use serde::de::DeserializeOwned;
+use warp::filters::body::FullBody;
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, VARY};
+use warp::http::{Response, StatusCode};
+use warp::{Buf, Filter, Rejection};
+
+/// Answers smaller than this aren't worth compressing.
+const MIN_SIZE: usize = 1024;
+
+/// How an answer is encoded, out of those the client accepts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+/// Rejection of a request body that can't be read.
+#[derive(Debug)]
+enum BodyError {
+    /// A `Content-Type` other than JSON or a `Content-Encoding` other than gzip or deflate.
+    Unsupported,
+    /// Larger than the limit once decompressed.
+    TooLarge,
+    Invalid(String),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::Unsupported => f.write_str("unsupported content type or encoding"),
+            BodyError::TooLarge => f.write_str("body too large"),
+            BodyError::Invalid(e) => f.write_str(e),
+        }
+    }
+}
+
+impl Error for BodyError {}
+
+/// The encoding to answer with from `Accept-Encoding`, gzip if it is acceptable and then deflate.
+fn negotiate(accept: &str) -> Encoding {
+    let mut gzip = false;
+    let mut deflate = false;
+
+    for value in accept.split(',') {
+        let mut parts = value.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or("").to_ascii_lowercase();
+        let acceptable = parts
+            .filter_map(|p| p.strip_prefix("q="))
+            .all(|q| q.parse::<f32>().map(|q| q > 0.0).unwrap_or(false));
+
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = acceptable,
+            "deflate" => deflate = acceptable,
+            "*" => {
+                gzip = gzip || acceptable;
+                deflate = deflate || acceptable;
+            }
+            _ => (),
+        }
+    }
+
+    if gzip {
+        Encoding::Gzip
+    } else if deflate {
+        Encoding::Deflate
+    } else {
+        Encoding::Identity
+    }
+}
+
+/// The encoding to answer the request with.
+pub fn accepted() -> impl Filter<Extract = (Encoding,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("accept-encoding")
+        .map(|accept: Option<String>| accept.map_or(Encoding::Identity, |a| negotiate(&a)))
+}
+
+/// Compresses `res` with `encoding`, unless it is too small to be worth it.
+pub fn compress(encoding: Encoding, res: Response<String>) -> Response<Vec<u8>> {
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .insert(VARY, HeaderValue::from_static("accept-encoding"));
+
+    // Writing to a `Vec` can't fail.
+    let compressed = match encoding {
+        _ if body.len() < MIN_SIZE => None,
+        Encoding::Identity => None,
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(body.as_bytes()).ok();
+            encoder.finish().ok().map(|b| (b, "gzip"))
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(body.as_bytes()).ok();
+            encoder.finish().ok().map(|b| (b, "deflate"))
+        }
+    };
+
+    match compressed {
+        Some((compressed, name)) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(name));
+            Response::from_parts(parts, compressed)
+        }
+        None => Response::from_parts(parts, body.into_bytes()),
+    }
+}
+
+/// Reads up to `limit` bytes of `body`, decompressing it as `encoding` says.
+fn decode(encoding: Option<&str>, body: &[u8], limit: u64) -> Result<Vec<u8>, BodyError> {
+    let reader: Box<Read> = match encoding.map(|e| e.trim().to_ascii_lowercase()) {
+        None => Box::new(body),
+        Some(ref e) if e == "identity" => Box::new(body),
+        Some(ref e) if e == "gzip" || e == "x-gzip" => Box::new(GzDecoder::new(body)),
+        Some(ref e) if e == "deflate" => Box::new(ZlibDecoder::new(body)),
+        Some(_) => return Err(BodyError::Unsupported),
+    };
+
+    // One byte past the limit to tell whether there's more.
+    let mut decoded = vec![];
+    reader
+        .take(limit + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e: io::Error| BodyError::Invalid(format!("{}", e)))?;
+    if decoded.len() as u64 > limit {
+        return Err(BodyError::TooLarge);
+    }
+
+    Ok(decoded)
+}
+
+/// A JSON body like `warp::body::json()`, that may be compressed with gzip or deflate as told by
+/// `Content-Encoding`. It is at most `limit` bytes once decompressed.
+pub fn json<T: DeserializeOwned + Send>(
+    limit: u64,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("content-type")
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::concat())
+        .and_then(
+            move |content_type: Option<String>, encoding: Option<String>, body: FullBody| {
+                if let Some(content_type) = content_type {
+                    let mime = content_type.split(';').next().unwrap_or("").trim();
+                    if !mime.eq_ignore_ascii_case("application/json") {
+                        return Err(warp::reject::custom(BodyError::Unsupported));
+                    }
+                }
+
+                let decoded = decode(encoding.as_deref(), body.bytes(), limit)
+                    .map_err(warp::reject::custom)?;
+                serde_json::from_slice(&decoded)
+                    .map_err(|e| warp::reject::custom(BodyError::Invalid(format!("{}", e))))
+            },
+        )
+}
+
+/// Answers the rejections of `json`.
+pub fn recover(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    let (status, body) = match rejection.find_cause::<BodyError>() {
+        Some(BodyError::Unsupported) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, String::new()),
+        Some(BodyError::TooLarge) => (StatusCode::PAYLOAD_TOO_LARGE, String::new()),
+        Some(BodyError::Invalid(e)) => (StatusCode::BAD_REQUEST, e.clone()),
+        None => return Err(rejection),
+    };
+
+    let mut res = Response::builder();
+    res.status(status);
+
+    Ok(res.body(body).expect("the response is valid"))
+}
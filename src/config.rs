@@ -0,0 +1,139 @@
+/// The options a `--config` file may set, with whether they may be repeated and how to check
+/// their values. They are those that can be changed while running.
+const OPTIONS: &[(&str, bool, fn(String) -> Result<(), String>)] = &[
+    ("notify-url", true, crate::valid_notify_url),
+    (
+        "namespace-notify-url",
+        true,
+        crate::valid_namespace_notify_url,
+    ),
+    ("notify-command", false, crate::valid_notify_command),
+    ("calendar", false, crate::valid_calendar),
+    ("read-only-token", true, valid_any),
+    ("jwt-secret", false, valid_any),
+    ("jwt-jwks-url", false, valid_any),
+    ("jwt-jwks-refresh", false, crate::valid_duration),
+    ("jwt-issuer", false, valid_any),
+    ("jwt-audience", false, valid_any),
+    ("jwt-tenant-claim", false, valid_any),
+    ("watch-interval", false, crate::valid_watch_interval),
+];
+
+/// The options of a `--config` file, as `option = value` lines with the options named as on the
+/// command line without the `--`. Blank lines and lines starting with `#` are skipped. Options
+/// that may be repeated may be on as many lines as they like.
+#[derive(Default)]
+pub struct Options {
+    values: Vec<(String, String)>,
+}
+
+fn valid_any(_: String) -> Result<(), String> {
+    Ok(())
+}
+
+/// Reads the options of `file`, or none without one.
+pub fn read(file: Option<&str>) -> Result<Options, String> {
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(Options::default()),
+    };
+
+    let contents = std::fs::read_to_string(file).map_err(|e| format!("{}; {}", file, e))?;
+    parse(&contents).map_err(|e| format!("{}; {}", file, e))
+}
+
+fn parse(contents: &str) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    for (i, line) in contents.lines().map(str::trim).enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(format!("line {}; should be option = value", i + 1)),
+        };
+        let (_, multiple, valid) = OPTIONS
+            .iter()
+            .find(|(option, _, _)| *option == name)
+            .ok_or_else(|| format!("line {}; {} can't be set here", i + 1, name))?;
+        if !multiple && options.value_of(name).is_some() {
+            return Err(format!("line {}; {} is already set", i + 1, name));
+        }
+        valid(value.to_owned()).map_err(|e| format!("line {}; {}", i + 1, e))?;
+
+        options.values.push((name.to_owned(), value.to_owned()));
+    }
+
+    if options.value_of("jwt-secret").is_some() && options.value_of("jwt-jwks-url").is_some() {
+        return Err("jwt-secret and jwt-jwks-url can't both be set".to_owned());
+    }
+
+    Ok(options)
+}
+
+impl Options {
+    pub fn value_of(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn values_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.values
+            .iter()
+            .filter(move |(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options() {
+        let options = parse(
+            "# condemn\n\
+             \n\
+             read-only-token = dashboard\n\
+             read-only-token=grafana\n\
+             jwt-issuer = https://id.example.net/ \n\
+             calendar = * 8-17 * * 1-5\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            options.values_of("read-only-token").collect::<Vec<_>>(),
+            vec!["dashboard", "grafana"]
+        );
+        assert_eq!(
+            options.value_of("jwt-issuer"),
+            Some("https://id.example.net/")
+        );
+        assert_eq!(options.value_of("calendar"), Some("* 8-17 * * 1-5"));
+        assert_eq!(options.value_of("jwt-audience"), None);
+    }
+
+    #[test]
+    fn keeps_equals_signs_in_values() {
+        let options = parse("notify-command = notify --to=ops\n").unwrap();
+        assert_eq!(options.value_of("notify-command"), Some("notify --to=ops"));
+    }
+
+    #[test]
+    fn refuses_invalid_options() {
+        for contents in &[
+            "read-only-token",
+            "store = redis",
+            "watch-interval = 1s\nwatch-interval = 2s",
+            "watch-interval = soon",
+            "calendar = * * *",
+            "jwt-secret = a\njwt-jwks-url = https://id.example.net/jwks",
+        ] {
+            assert!(parse(contents).is_err(), "{}", contents);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use chrono::Utc;
+use openssl::sha::Sha256;
+use warp::http::header::ETAG;
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection};
+
+use crate::Switch;
+
+/// A weak ETag for an answer showing `switches` as they are now. The seconds until their
+/// deadlines are left out, as they change every second while nothing else does, so answers with
+/// the same tag differ only in those.
+pub fn of(switches: &[Switch]) -> String {
+    let now = Utc::now();
+    let mut hasher = Sha256::new();
+
+    for s in switches {
+        // Switches always serialize.
+        hasher.update(&serde_json::to_vec(s).unwrap_or_default());
+        hasher.update(&[s.in_window(now) as u8, s.overdue(now) as u8]);
+    }
+
+    format!("W/\"{}\"", hex::encode(&hasher.finish()[..16]))
+}
+
+/// The request's `If-None-Match`.
+pub fn if_none_match() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("if-none-match")
+}
+
+/// Whether `if_none_match` has `etag`, so the client's copy of the answer is still good. Tags are
+/// compared weakly, ignoring `W/`.
+pub fn matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = weak(etag);
+
+    if_none_match.is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == "*" || weak(tag) == etag)
+    })
+}
+
+/// `304 Not Modified` for a client whose copy has the tag `etag`.
+pub fn not_modified(etag: &str) -> Response<String> {
+    let mut res = Response::builder();
+    res.status(StatusCode::NOT_MODIFIED);
+    res.header(ETAG, etag);
+
+    res.body(String::new()).expect("the response is valid")
+}
@@ -0,0 +1,166 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::{ok, Either};
+use futures::sync::mpsc;
+use futures::{Future, Stream};
+use log::{info, warn};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use parking_lot::Mutex;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Timeout};
+use tokio_tls::{TlsAcceptor, TlsStream};
+
+use crate::proxy::{self, Connection};
+
+/// How long a client has to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection once its handshake is done.
+type Accepted = TlsStream<Connection<TcpStream>>;
+
+/// The certificate condemn serves HTTPS with. The files are checked on every connection so that a
+/// renewed certificate is used as soon as it is written, without a restart.
+pub struct Certificate {
+    cert_file: String,
+    key_file: String,
+    current: Mutex<Loaded>,
+}
+
+struct Loaded {
+    /// When the certificate and key files were last changed, as of loading them.
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    acceptor: TlsAcceptor,
+}
+
+fn modified(file: &str) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+/// An acceptor for the PEM certificate, followed by any intermediates, and key in the files.
+fn load(cert_file: &str, key_file: &str) -> Result<TlsAcceptor, String> {
+    let pem = fs::read(cert_file).map_err(|e| format!("{}; {}", cert_file, e))?;
+    let mut certs = X509::stack_from_pem(&pem)
+        .map_err(|e| format!("{}; {}", cert_file, e))?
+        .into_iter();
+    let cert = certs
+        .next()
+        .ok_or_else(|| format!("{}; no certificate", cert_file))?;
+
+    let mut chain = Stack::new().map_err(|e| format!("{}", e))?;
+    for intermediate in certs {
+        chain.push(intermediate).map_err(|e| format!("{}", e))?;
+    }
+
+    let pem = fs::read(key_file).map_err(|e| format!("{}; {}", key_file, e))?;
+    let key = PKey::private_key_from_pem(&pem).map_err(|e| format!("{}; {}", key_file, e))?;
+
+    // native-tls only takes PKCS#12, so the PEM is repackaged in memory.
+    let mut builder = Pkcs12::builder();
+    builder.ca(chain);
+    let der = builder
+        .build("", "condemn", &key, &cert)
+        .and_then(|archive| archive.to_der())
+        .map_err(|e| format!("{}", e))?;
+    let identity = native_tls::Identity::from_pkcs12(&der, "").map_err(|e| format!("{}", e))?;
+
+    native_tls::TlsAcceptor::new(identity)
+        .map(TlsAcceptor::from)
+        .map_err(|e| format!("{}; {}", cert_file, e))
+}
+
+impl Certificate {
+    pub fn new(cert_file: &str, key_file: &str) -> Result<Self, String> {
+        Ok(Certificate {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            current: Mutex::new(Loaded {
+                modified: (modified(cert_file), modified(key_file)),
+                acceptor: load(cert_file, key_file)?,
+            }),
+        })
+    }
+
+    /// The acceptor for the certificate as it is now. If the files have changed but don't load,
+    /// as when only one of them has been written so far, the one before is kept until they change
+    /// again.
+    fn acceptor(&self) -> TlsAcceptor {
+        let mut current = self.current.lock();
+        let now = (modified(&self.cert_file), modified(&self.key_file));
+
+        if now != current.modified {
+            current.modified = now;
+
+            match load(&self.cert_file, &self.key_file) {
+                Ok(acceptor) => {
+                    info!("reloaded TLS certificate; file={}", self.cert_file);
+                    current.acceptor = acceptor;
+                }
+                Err(e) => warn!("failed to reload TLS certificate, keeping the last; {}", e),
+            }
+        }
+
+        current.acceptor.clone()
+    }
+}
+
+/// Listens on `addr` for HTTPS. The returned future accepts connections and must be spawned,
+/// the stream yields them once their handshakes are done for `warp::serve().serve_incoming()`.
+/// With `proxy_protocol` each connection must start with a PROXY protocol header. Either way the
+/// TLS stream reads through a `proxy::Connection`, so requests know the client's address for
+/// `--checkin-limit-ip` and the logs as they do over HTTP.
+pub fn listen(
+    addr: &SocketAddr,
+    certificate: Certificate,
+    proxy_protocol: bool,
+) -> Result<
+    (
+        impl Future<Item = (), Error = ()>,
+        impl Stream<Item = Accepted, Error = io::Error>,
+    ),
+    String,
+> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("{}; {}", addr, e))?;
+    let certificate = Arc::new(certificate);
+    let (tx, rx) = mpsc::unbounded();
+
+    // Handshakes run on their own so that a slow client doesn't hold up the others.
+    let accept = listener.incoming().then(Ok).for_each(move |tcp| match tcp {
+        Ok(tcp) => {
+            let tx = tx.clone();
+            let certificate = Arc::clone(&certificate);
+            let connection = if proxy_protocol {
+                Either::A(proxy::accept(tcp))
+            } else {
+                Either::B(ok(Connection::direct(tcp)))
+            };
+            let handshake = connection
+                .map_err(|e| info!("PROXY protocol header refused; {}", e))
+                .and_then(move |connection| {
+                    Timeout::new(certificate.acceptor().accept(connection), HANDSHAKE_TIMEOUT)
+                        .map_err(|e| info!("TLS handshake failed; {}", e))
+                })
+                .map(move |tls| {
+                    let _ = tx.unbounded_send(tls);
+                });
+            tokio::spawn(handshake);
+
+            Either::A(ok(()))
+        }
+        // Such as running out of file descriptors. Give it a moment rather than spin.
+        Err(e) => {
+            warn!("accept failure; {}", e);
+            Either::B(Delay::new(Instant::now() + Duration::from_millis(100)).map_err(|_| ()))
+        }
+    });
+
+    let incoming = rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "accept loop ended"));
+
+    Ok((accept, incoming))
+}
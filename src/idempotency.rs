@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use warp::filters::path::FullPath;
+use warp::http::header::{HeaderValue, CONTENT_TYPE};
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection};
+
+use crate::auth::{self, Caller};
+
+/// The answers to requests with an `Idempotency-Key`, kept for `ttl` so that a retry is given the
+/// same answer rather than being done again.
+pub struct Answers {
+    ttl: Duration,
+    answers: Mutex<HashMap<Id, Kept>>,
+}
+
+/// A key is only for the same route and caller.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Id {
+    caller: Option<String>,
+    path: String,
+    key: String,
+}
+
+struct Kept {
+    at: Instant,
+    /// `None` while the request is still being answered.
+    answer: Option<Answer>,
+}
+
+#[derive(Debug, Clone)]
+struct Answer {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: String,
+}
+
+/// Rejection of a request with a key that has been seen, with the answer given to it if there is
+/// one yet.
+#[derive(Debug)]
+struct Seen(Option<Answer>);
+
+impl fmt::Display for Seen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("idempotency key already used")
+    }
+}
+
+impl Error for Seen {}
+
+/// The key of a request being answered, to keep the answer for. Dropping it without keeping one,
+/// such as when the handler fails, forgets the key so that the request can be retried.
+pub struct Key {
+    id: Option<Id>,
+    answers: Arc<Answers>,
+}
+
+impl Answers {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            answers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `id` for a new request, or says what the request that took it was answered with.
+    fn begin(&self, id: &Id) -> Result<(), Seen> {
+        let mut answers = self.answers.lock();
+
+        match answers.get(id) {
+            Some(kept) if kept.at.elapsed() < self.ttl => Err(Seen(kept.answer.clone())),
+            _ => {
+                answers.insert(
+                    id.clone(),
+                    Kept {
+                        at: Instant::now(),
+                        answer: None,
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Forgets the answers kept for longer than `ttl`.
+    pub fn prune(&self) {
+        let ttl = self.ttl;
+        self.answers
+            .lock()
+            .retain(|_, kept| kept.at.elapsed() < ttl);
+    }
+}
+
+impl Key {
+    /// Keeps `res` as the answer for the key, if the request had one. Server errors are not kept
+    /// so that a retry may succeed.
+    pub fn keep(mut self, res: Response<String>) -> Response<String> {
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => return res,
+        };
+
+        let mut answers = self.answers.answers.lock();
+        if res.status().is_server_error() {
+            answers.remove(&id);
+        } else {
+            let answer = Answer {
+                status: res.status(),
+                content_type: res.headers().get(CONTENT_TYPE).cloned(),
+                body: res.body().clone(),
+            };
+            answers.insert(
+                id,
+                Kept {
+                    at: Instant::now(),
+                    answer: Some(answer),
+                },
+            );
+        }
+
+        res
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.answers.answers.lock().remove(&id);
+        }
+    }
+}
+
+/// The request's `Idempotency-Key`. A request with a key that has been seen is given to
+/// `recover` to be answered as the first was, or with 409 if it is still being answered. It goes
+/// just before `audit::record` so that a retry isn't recorded again.
+pub fn key(answers: Arc<Answers>) -> impl Filter<Extract = (Key,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("idempotency-key")
+        .and(warp::path::full())
+        .and(auth::caller())
+        .and_then(move |key: Option<String>, path: FullPath, caller: Caller| {
+            let answers = Arc::clone(&answers);
+            let id = match key {
+                Some(key) => Id {
+                    caller: caller.0,
+                    path: path.as_str().to_owned(),
+                    key,
+                },
+                None => return Ok(Key { id: None, answers }),
+            };
+
+            match answers.begin(&id) {
+                Ok(()) => Ok(Key {
+                    id: Some(id),
+                    answers,
+                }),
+                Err(seen) => Err(warp::reject::custom(seen)),
+            }
+        })
+}
+
+/// Answers the rejections of `key` with the answer given to the first request, or 409.
+pub fn recover(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    let seen = match rejection.find_cause::<Seen>() {
+        Some(seen) => seen,
+        None => return Err(rejection),
+    };
+
+    let mut res = Response::builder();
+    match &seen.0 {
+        Some(answer) => {
+            res.status(answer.status);
+            if let Some(content_type) = &answer.content_type {
+                res.header(CONTENT_TYPE, content_type.clone());
+            }
+            res.header("idempotent-replayed", "true");
+            Ok(res
+                .body(answer.body.clone())
+                .expect("the response is valid"))
+        }
+        None => {
+            res.status(StatusCode::CONFLICT);
+            Ok(res.body(String::new()).expect("the response is valid"))
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::info;
+use parking_lot::Mutex;
+use warp::http::header::RETRY_AFTER;
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection};
+
+use crate::{auth, proxy};
+
+/// A token bucket for each key, holding up to `capacity` tokens and refilled at `per_sec`.
+pub struct Limiter<K> {
+    capacity: f64,
+    per_sec: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// How often check-ins may come from one address and for one switch.
+pub struct Limits {
+    pub ip: Option<Limiter<IpAddr>>,
+    pub switch: Option<Limiter<String>>,
+}
+
+/// Rejection of a check-in over its limit.
+#[derive(Debug)]
+struct TooManyRequests {
+    retry_after: Duration,
+}
+
+impl fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("too many requests")
+    }
+}
+
+impl Error for TooManyRequests {}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+/// Parses a rate as `count/duration`, such as `10/1m`.
+pub fn parse_rate(v: &str) -> Result<(u32, Duration), String> {
+    let (count, per) = match v.find('/') {
+        Some(i) => (&v[..i], &v[i + 1..]),
+        None => return Err("should be count/duration, such as 10/1m".to_owned()),
+    };
+
+    let count: u32 = count.parse().map_err(|e| format!("{}", e))?;
+    let per = humantime::parse_duration(per).map_err(|e| format!("{}", e))?;
+    if count == 0 || per == Duration::from_secs(0) {
+        return Err("should allow at least one request".to_owned());
+    }
+
+    Ok((count, per))
+}
+
+impl<K: Hash + Eq> Limiter<K> {
+    /// Allows bursts of up to `count` requests, and `count` every `per` after that.
+    pub fn new(count: u32, per: Duration) -> Self {
+        Limiter {
+            capacity: f64::from(count),
+            per_sec: f64::from(count) / secs(per),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`, or says how long until there is one.
+    fn take(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+
+        let elapsed = secs(now - bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.per_sec;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+
+    /// Forgets the buckets that would be full by now, as a new one would be the same.
+    fn prune(&self) {
+        let now = Instant::now();
+        let (capacity, per_sec) = (self.capacity, self.per_sec);
+
+        self.buckets
+            .lock()
+            .retain(|_, bucket| bucket.tokens + secs(now - bucket.updated) * per_sec < capacity);
+    }
+}
+
+impl Limits {
+    /// Takes a token for a check-in of `name` from `addr` from each limit.
+    pub fn take(&self, addr: Option<IpAddr>, name: &str) -> Result<(), Duration> {
+        if let (Some(ip), Some(addr)) = (&self.ip, addr) {
+            ip.take(addr)?;
+        }
+        if let Some(switch) = &self.switch {
+            switch.take(name.to_owned())?;
+        }
+        Ok(())
+    }
+
+    pub fn prune(&self) {
+        if let Some(ip) = &self.ip {
+            ip.prune();
+        }
+        if let Some(switch) = &self.switch {
+            switch.prune();
+        }
+    }
+}
+
+/// The switch name path segment, as `auth::name()`, of a check-in within `limits`. Those over it
+/// are given to `recover` to be answered with 429.
+pub fn name(limits: Arc<Limits>) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    proxy::client()
+        .and(auth::name())
+        .and_then(
+            move |addr: Option<IpAddr>, name: String| match limits.take(addr, &name) {
+                Ok(()) => Ok(name),
+                Err(retry_after) => {
+                    info!("rate limited check-in; name={}, addr={:?}", name, addr);
+                    Err(warp::reject::custom(TooManyRequests { retry_after }))
+                }
+            },
+        )
+}
+
+/// Answers the rejections of `name` with 429 and when to try again.
+pub fn recover(rejection: Rejection) -> Result<Response<String>, Rejection> {
+    let retry_after = match rejection.find_cause::<TooManyRequests>() {
+        Some(too_many) => too_many.retry_after,
+        None => return Err(rejection),
+    };
+
+    // Retry-After is in whole seconds, so round up rather than have the client come back early.
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    let mut res = Response::builder();
+    res.status(StatusCode::TOO_MANY_REQUESTS);
+    res.header(RETRY_AFTER, secs.to_string());
+
+    Ok(res.body(String::new()).expect("the response is valid"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("10/1m"), Ok((10, Duration::from_secs(60))));
+        assert_eq!(parse_rate("1/500ms"), Ok((1, Duration::from_millis(500))));
+        assert_eq!(
+            parse_rate("120/1h 30m"),
+            Ok((120, Duration::from_secs(90 * 60)))
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_rates() {
+        for v in &[
+            "10",
+            "/1m",
+            "10/",
+            "ten/1m",
+            "-1/1m",
+            "10/1 fortnight",
+            "0/1m",
+            "10/0s",
+        ] {
+            assert!(parse_rate(v).is_err(), "{}", v);
+        }
+    }
+}
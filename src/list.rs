@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::Switch;
+
+/// The query string of `GET /`. Everything is optional; without any of it every switch is listed.
+#[derive(Deserialize)]
+pub struct ListOptions {
+    /// Only switches whose name starts with this.
+    prefix: Option<String>,
+    /// Comma separated label selectors a switch must match all of. See `Selector`.
+    labels: Option<String>,
+    /// Comma separated tags, `key:value` or `key`, a switch must have all of. `team:payments` is
+    /// the same as the label selector `team=payments`.
+    tag: Option<String>,
+    /// Only switches with a deadline at or after this time.
+    deadline_after: Option<DateTime<Utc>>,
+    /// Only switches with a deadline before this time.
+    deadline_before: Option<DateTime<Utc>>,
+    /// At most this many switches. If there are more the response has an `X-Next-Cursor` header.
+    limit: Option<usize>,
+    /// The `X-Next-Cursor` of the previous page, to carry on from there.
+    cursor: Option<String>,
+    /// `name`, the default, or `deadline`. Prefixed with `-` to reverse it.
+    sort: Option<String>,
+    /// Comma separated fields to include of each switch rather than all of them.
+    fields: Option<String>,
+}
+
+/// The fields of a switch as they are named in JSON.
+const FIELDS: &[&str] = &[
+    "name",
+    "deadline",
+    "window_start",
+    "state",
+    "paused_at",
+    "started_at",
+    "max_runtime",
+    "last_runtime",
+    "deadline_after_run",
+    "misses_before_alert",
+    "misses",
+    "cadence_tolerance",
+    "late_tolerance",
+    "late_since",
+    "warn_before",
+    "warned",
+    "renotify_every",
+    "payload",
+    "history",
+    "cron",
+    "description",
+    "owner",
+    "labels",
+    "message",
+    "priority",
+    "callback",
+    "depends_on",
+    "calendar",
+    "seconds_until_deadline",
+    "in_window",
+    "overdue",
+];
+
+#[derive(Clone, Copy)]
+enum Sort {
+    Name,
+    Deadline,
+}
+
+/// Where a switch falls in a listing, which is all a cursor needs to find the next page whichever
+/// way it is sorted.
+#[derive(Serialize, Deserialize)]
+struct Position {
+    deadline: DateTime<Utc>,
+    name: String,
+}
+
+impl From<&Switch> for Position {
+    fn from(s: &Switch) -> Self {
+        Position {
+            deadline: s.deadline,
+            name: s.name.clone(),
+        }
+    }
+}
+
+/// A condition on the labels of a switch.
+enum Selector {
+    /// `key=value`
+    Equals(String, String),
+    /// `key!=value`, which a switch without the label also matches.
+    NotEquals(String, String),
+    /// `key`, the switch has the label with any value.
+    Exists(String),
+}
+
+impl Selector {
+    /// A tag as `key:value`, or just `key` for the label with any value.
+    fn parse_tag(s: &str) -> Result<Self, String> {
+        let selector = match s.find(':') {
+            Some(i) => Selector::Equals(s[..i].to_owned(), s[i + 1..].to_owned()),
+            None => Selector::Exists(s.to_owned()),
+        };
+
+        match &selector {
+            Selector::Equals(key, _) | Selector::Exists(key) if key.is_empty() => {
+                Err(format!("tag '{}' has no key", s))
+            }
+            _ => Ok(selector),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let selector = if let Some(i) = s.find("!=") {
+            Selector::NotEquals(s[..i].to_owned(), s[i + 2..].to_owned())
+        } else if let Some(i) = s.find('=') {
+            Selector::Equals(s[..i].to_owned(), s[i + 1..].to_owned())
+        } else {
+            Selector::Exists(s.to_owned())
+        };
+
+        match &selector {
+            Selector::Equals(key, _) | Selector::NotEquals(key, _) | Selector::Exists(key)
+                if key.is_empty() =>
+            {
+                Err(format!("label selector '{}' has no key", s))
+            }
+            _ => Ok(selector),
+        }
+    }
+
+    fn matches(&self, s: &Switch) -> bool {
+        let labels = &s.metadata.labels;
+
+        match self {
+            Selector::Equals(key, value) => labels.get(key) == Some(value),
+            Selector::NotEquals(key, value) => labels.get(key) != Some(value),
+            Selector::Exists(key) => labels.contains_key(key),
+        }
+    }
+}
+
+/// `ListOptions` checked and ready to apply.
+pub struct Listing {
+    prefix: Option<String>,
+    selectors: Vec<Selector>,
+    deadline_after: Option<DateTime<Utc>>,
+    deadline_before: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+    after: Option<Position>,
+    sort: Sort,
+    descending: bool,
+    fields: Option<Vec<String>>,
+}
+
+/// A page of switches and the cursor for the next one, if there is one.
+pub struct Page {
+    pub switches: Vec<Switch>,
+    pub next: Option<String>,
+}
+
+impl ListOptions {
+    pub fn parse(self) -> Result<Listing, String> {
+        let mut selectors: Vec<Selector> = match self.labels {
+            None => vec![],
+            Some(labels) => labels
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(Selector::parse)
+                .collect::<Result<_, _>>()?,
+        };
+        if let Some(tags) = self.tag {
+            for tag in tags.split(',').filter(|s| !s.is_empty()) {
+                selectors.push(Selector::parse_tag(tag)?);
+            }
+        }
+
+        if self.limit == Some(0) {
+            return Err("limit must be at least 1".to_owned());
+        }
+
+        let after = match self.cursor {
+            None => None,
+            Some(cursor) => Some(decode_cursor(&cursor)?),
+        };
+
+        let sort = self.sort.unwrap_or_default();
+        let descending = sort.starts_with('-');
+        let sort = match sort.trim_start_matches('-') {
+            "" | "name" => Sort::Name,
+            "deadline" => Sort::Deadline,
+            other => return Err(format!("can't sort by '{}'", other)),
+        };
+
+        let fields = match self.fields {
+            None => None,
+            Some(fields) => {
+                let fields: Vec<String> = fields
+                    .split(',')
+                    .filter(|f| !f.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+
+                if let Some(unknown) = fields.iter().find(|f| !FIELDS.contains(&f.as_str())) {
+                    return Err(format!("unknown field '{}'", unknown));
+                }
+
+                Some(fields)
+            }
+        };
+
+        Ok(Listing {
+            prefix: self.prefix,
+            selectors,
+            deadline_after: self.deadline_after,
+            deadline_before: self.deadline_before,
+            limit: self.limit,
+            after,
+            sort,
+            descending,
+            fields,
+        })
+    }
+}
+
+impl Listing {
+    /// Whether it picks switches by their labels, as operations on many switches at once must so
+    /// that they can't be applied to every switch by mistake.
+    pub fn has_selectors(&self) -> bool {
+        !self.selectors.is_empty()
+    }
+
+    fn matches(&self, s: &Switch) -> bool {
+        self.prefix.iter().all(|p| s.name.starts_with(p))
+            && self.deadline_after.iter().all(|t| &s.deadline >= t)
+            && self.deadline_before.iter().all(|t| &s.deadline < t)
+            && self.selectors.iter().all(|selector| selector.matches(s))
+            && self
+                .after
+                .iter()
+                .all(|after| self.cmp(&Position::from(s), after) == Ordering::Greater)
+    }
+
+    /// The order of the listing. Ties are broken by name so that it is the same every time and
+    /// pages follow on from each other however the store orders them.
+    fn cmp(&self, a: &Position, b: &Position) -> Ordering {
+        let ord = match self.sort {
+            Sort::Name => a.name.cmp(&b.name),
+            Sort::Deadline => a.deadline.cmp(&b.deadline).then(a.name.cmp(&b.name)),
+        };
+
+        if self.descending {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+
+    /// The switches that match, in order.
+    pub fn page(&self, switches: Vec<Switch>) -> Page {
+        let mut switches: Vec<Switch> = switches.into_iter().filter(|s| self.matches(s)).collect();
+        switches.sort_by(|a, b| self.cmp(&a.into(), &b.into()));
+
+        let next = match self.limit {
+            Some(limit) if switches.len() > limit => {
+                switches.truncate(limit);
+                switches.last().map(|s| encode_cursor(&s.into()))
+            }
+            _ => None,
+        };
+
+        Page { switches, next }
+    }
+
+    /// `switches` as a JSON array, with only the chosen fields if there are any.
+    pub fn to_json(&self, switches: &[Switch]) -> Result<String, serde_json::Error> {
+        let selected = switches
+            .iter()
+            .map(|s| {
+                let mut all = public_json(s)?;
+
+                // Fields a switch leaves out, such as an unset owner, are left out here as well.
+                Ok(match &self.fields {
+                    None => all,
+                    Some(fields) => fields
+                        .iter()
+                        .filter_map(|f| all.remove(f).map(|v| (f.clone(), v)))
+                        .collect(),
+                })
+            })
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+
+        serde_json::to_string(&selected)
+    }
+}
+
+/// A switch as the API shows it, which is as it is stored without the hash of its token or its
+/// history, and with what clients would otherwise each work out from the deadline and window
+/// themselves.
+pub fn public_json(
+    s: &Switch,
+) -> Result<serde_json::Map<String, serde_json::Value>, serde_json::Error> {
+    let now = Utc::now();
+
+    match serde_json::to_value(s)? {
+        serde_json::Value::Object(mut all) => {
+            all.remove("token_hash");
+            all.remove("history");
+            all.insert("seconds_until_deadline".to_owned(), s.remaining(now).into());
+            all.insert("in_window".to_owned(), s.in_window(now).into());
+            all.insert("overdue".to_owned(), s.overdue(now).into());
+            Ok(all)
+        }
+        _ => unreachable!("switches serialize as objects"),
+    }
+}
+
+/// Cursors are where the last switch of the page before falls, kept opaque so that clients don't
+/// come to rely on what is in them.
+fn encode_cursor(position: &Position) -> String {
+    let json = serde_json::to_vec(position).expect("positions always serialize");
+    base64::encode_config(&json, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_cursor(cursor: &str) -> Result<Position, String> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| "invalid cursor".to_owned())
+}
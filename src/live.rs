@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use futures::{Future, Sink, Stream};
+use log::info;
+use serde_json::json;
+use warp::ws::{Message, WebSocket, Ws2};
+
+use crate::auth::Scope;
+use crate::list;
+use crate::stores::{Change, Store, WatchedStore};
+
+/// A text message of `value`.
+fn message(value: serde_json::Value) -> Message {
+    Message::text(value.to_string())
+}
+
+/// The message for `change`, or None if it isn't to a switch in `scope`.
+fn change_message(scope: &Scope, change: Change) -> Option<Message> {
+    match change {
+        Change::Updated(s) => {
+            let s = list::public_json(&scope.local(*s)?).ok()?;
+            Some(message(json!({ "type": "updated", "switch": s })))
+        }
+        Change::Removed(name) => {
+            let name = scope.local_name(&name)?;
+            Some(message(json!({ "type": "removed", "name": name })))
+        }
+    }
+}
+
+/// Sends every switch in `scope` and then each change to them until the client goes away.
+fn live<S: 'static + Store + Send + Sync>(
+    store: Arc<WatchedStore<S>>,
+    scope: Scope,
+    socket: WebSocket,
+) -> impl Future<Item = (), Error = ()> {
+    // Watching first means nothing is missed while the snapshot is taken. A change already in it
+    // is sent again, which does no harm.
+    let changes = store.watch();
+    let (tx, rx) = socket.split();
+
+    let snapshot_scope = scope.clone();
+    let snapshot = store.all().map(move |all| {
+        let switches: Vec<_> = all
+            .into_iter()
+            .filter_map(|s| snapshot_scope.local(s))
+            .filter_map(|s| list::public_json(&s).ok())
+            .collect();
+        message(json!({ "type": "snapshot", "switches": switches }))
+    });
+
+    let messages = snapshot
+        .into_stream()
+        .chain(changes.filter_map(move |change| change_message(&scope, change)));
+
+    let send = tx
+        .sink_map_err(|e| info!("live update socket closed; {}", e))
+        .send_all(messages)
+        .map(|_| ());
+
+    // Reading is what answers pings and notices the client closing.
+    let recv = rx
+        .for_each(|_| Ok(()))
+        .map_err(|e| info!("live update socket closed; {}", e));
+
+    send.select(recv).then(|_| Ok(()))
+}
+
+/// Upgrades `GET /events` to a WebSocket of live updates to the switches in `scope`.
+pub fn handle<S: 'static + Store + Send + Sync>(
+    store: Arc<WatchedStore<S>>,
+    scope: Scope,
+    ws: Ws2,
+) -> impl warp::Reply {
+    ws.on_upgrade(move |socket| live(store, scope, socket))
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use warp::filters::log::{Info, Log};
+use warp::http::{Response, StatusCode};
+
+use crate::access;
+use crate::auth::Scope;
+use crate::watcher::Health;
+
+/// The upper bounds, in seconds, of the buckets of the duration histograms.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long things took, in `BUCKETS`.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// How many took at most each of `BUCKETS`.
+    buckets: [u64; 11],
+    seconds: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, seconds: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *le {
+                *bucket += 1;
+            }
+        }
+        self.seconds += seconds;
+        self.count += 1;
+    }
+
+    /// The samples of `name` in the Prometheus text format, with `labels` such as `route="put"`
+    /// if there are any.
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (count, le) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, le, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        );
+
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.seconds);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+/// The requests answered by each route and how the watcher is doing, for `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<&'static str, Route>>,
+    pub watcher: Arc<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Route {
+    statuses: BTreeMap<u16, u64>,
+    duration: Histogram,
+}
+
+impl Metrics {
+    fn observe(&self, route: &'static str, status: u16, seconds: f64) {
+        let mut routes = self.routes.lock();
+        let route = routes.entry(route).or_default();
+
+        *route.statuses.entry(status).or_insert(0) += 1;
+        route.duration.observe(seconds);
+    }
+
+    /// In the Prometheus text format.
+    fn render(&self) -> String {
+        let routes = self.routes.lock();
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP condemn_http_requests_total Requests answered, by route and status.\n",
+        );
+        out.push_str("# TYPE condemn_http_requests_total counter\n");
+        for (name, route) in routes.iter() {
+            for (status, count) in &route.statuses {
+                let _ = writeln!(
+                    out,
+                    "condemn_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    name, status, count
+                );
+            }
+        }
+
+        out.push_str("# HELP condemn_http_request_duration_seconds How long requests took to answer, by route.\n");
+        out.push_str("# TYPE condemn_http_request_duration_seconds histogram\n");
+        for (name, route) in routes.iter() {
+            route.duration.render(
+                &mut out,
+                "condemn_http_request_duration_seconds",
+                &format!("route=\"{}\"", name),
+            );
+        }
+
+        self.watcher.render(&mut out);
+
+        out
+    }
+}
+
+/// Counts each request for the route that answered it, or `other` for those that none did such
+/// as those refused for their credentials.
+pub fn record(metrics: Arc<Metrics>) -> Log<impl Fn(Info) + Clone> {
+    warp::log::custom(move |info: Info| {
+        let elapsed = info.elapsed();
+        metrics.observe(
+            access::route_name().unwrap_or("other"),
+            info.status().as_u16(),
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9,
+        );
+    })
+}
+
+/// Answers `GET /metrics`. Namespaced requests are refused as the metrics are of every switch.
+pub fn handle(metrics: Arc<Metrics>, scope: Scope) -> Response<String> {
+    let mut res = Response::builder();
+
+    if scope.is_namespaced() {
+        res.status(StatusCode::FORBIDDEN);
+        return res.body(String::new()).unwrap();
+    }
+
+    res.header("content-type", "text/plain; version=0.0.4");
+    res.body(metrics.render()).unwrap()
+}
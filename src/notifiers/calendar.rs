@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use parking_lot::Mutex;
+
+use crate::notifiers::{Event, Notifier};
+use crate::schedule::Schedule;
+use crate::Switch;
+
+/// Notifications of switches whose calendar is closed, kept until it opens. Only the last of each
+/// kind is kept for a switch, so one that keeps notifying while it is closed is heard from once.
+/// They are kept in memory and lost if condemn stops before they are sent.
+pub struct Held {
+    notifications: Mutex<HashMap<(String, &'static str), Notification>>,
+}
+
+struct Notification {
+    at: DateTime<Utc>,
+    switch: Switch,
+    event: Event,
+}
+
+impl Held {
+    pub fn new() -> Self {
+        Self {
+            notifications: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps the notification if the switch's calendar, or `default` if it has none, is closed at
+    /// `now`, answering whether it did.
+    pub fn hold(
+        &self,
+        s: &Switch,
+        event: Event,
+        default: Option<&Schedule>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let calendar = match s.metadata.calendar.as_ref().or(default) {
+            Some(calendar) if !calendar.contains(now) => calendar,
+            _ => return false,
+        };
+
+        // Schedules are checked to run when parsed, so it will open.
+        let at = match calendar.next_after(now) {
+            Some(at) => at,
+            None => return false,
+        };
+
+        info!(
+            "holding notification until its calendar opens; name={}, event={}, until={}",
+            s.name,
+            event.name(),
+            at.to_rfc3339()
+        );
+        self.notifications.lock().insert(
+            (s.name.clone(), event.name()),
+            Notification {
+                at,
+                switch: s.clone(),
+                event,
+            },
+        );
+
+        true
+    }
+
+    /// Sends the notifications whose calendars have opened as of `now` to `notifier`.
+    pub fn release<N: Notifier + ?Sized>(&self, notifier: &N, now: DateTime<Utc>) {
+        let due: Vec<Notification> = {
+            let mut notifications = self.notifications.lock();
+            let keys: Vec<_> = notifications
+                .iter()
+                .filter(|(_, n)| n.at <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter()
+                .filter_map(|key| notifications.remove(key))
+                .collect()
+        };
+
+        for n in due {
+            notifier.notify(&n.switch, n.event);
+        }
+    }
+}
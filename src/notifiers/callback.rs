@@ -0,0 +1,298 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use futures::sync::oneshot;
+use futures::Future;
+use log::warn;
+use reqwest::header::HOST;
+use reqwest::RedirectPolicy;
+use url::{Host, Url};
+
+use crate::notifiers::{event_json, send, spawn, Event, Notifier};
+use crate::proxy::{parse_network, Network};
+use crate::Switch;
+
+/// Addresses that aren't on the internet, which callbacks aren't posted to unless a network of
+/// `--callback-allow` has them: this host, private and shared networks, link-local, multicast
+/// and reserved addresses.
+const INTERNAL: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/96",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// A host or network callbacks may be posted to.
+#[derive(Debug, Clone)]
+pub enum Allow {
+    /// A host name, which the URL has to name exactly. Its addresses must be on the internet.
+    Host(String),
+    /// Addresses, given in the URL or that its host resolves to, internal or not.
+    Network(Network),
+}
+
+/// The hosts and networks of `--callback-allow`. Callbacks are refused unless there is at least
+/// one, so that only operators who want them open condemn up to posting to URLs its clients give.
+pub struct Allowlist {
+    allowed: Vec<Allow>,
+    internal: Vec<Network>,
+}
+
+/// Parses an entry of `--callback-allow`, a network as `address/prefix`, an address or a host
+/// name.
+pub fn parse_allow(v: &str) -> Result<Allow, String> {
+    if let Ok(network) = parse_network(v) {
+        return Ok(Allow::Network(network));
+    }
+
+    match Host::parse(v) {
+        Ok(Host::Domain(host)) if !host.is_empty() => Ok(Allow::Host(host)),
+        _ => Err("should be a host name, an address or address/prefix".to_owned()),
+    }
+}
+
+impl Allowlist {
+    pub fn new(allowed: Vec<Allow>) -> Self {
+        Allowlist {
+            allowed,
+            internal: INTERNAL
+                .iter()
+                .map(|v| parse_network(v).expect("internal networks should parse. This is a bug!"))
+                .collect(),
+        }
+    }
+
+    /// Whether `callback` is an HTTP or HTTPS URL to an allowed host or address, that misses can
+    /// be posted to once its host resolves to allowed addresses.
+    pub fn permits(&self, callback: &str) -> bool {
+        let url = match Url::parse(callback) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return false;
+        }
+
+        match url.host() {
+            Some(Host::Domain(host)) => self.allowed.iter().any(|allow| match allow {
+                Allow::Host(allowed) => allowed == host,
+                Allow::Network(_) => false,
+            }),
+            Some(Host::Ipv4(ip)) => self.permits_addr(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => self.permits_addr(IpAddr::V6(ip)),
+            None => false,
+        }
+    }
+
+    /// Whether a callback may be posted to `ip`: one in an allowed network, or any on the internet
+    /// for an allowed host.
+    fn permits_addr(&self, ip: IpAddr) -> bool {
+        let mut hosts = false;
+        for allow in &self.allowed {
+            match allow {
+                Allow::Network(network) if network.contains(ip) => return true,
+                Allow::Network(_) => (),
+                Allow::Host(_) => hosts = true,
+            }
+        }
+
+        hosts && !self.internal.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Posts the misses of switches that were registered with a `callback` of their own to it, so
+/// that their owners can hook them up to whatever they like. Check-ins, early or irregular,
+/// aren't posted.
+///
+/// The URL is checked against the allowlist again before every post, as it may have been stored
+/// before the allowlist changed, and its host is resolved first so that a name can't lead to an
+/// internal address. Plain HTTP is posted to the address that was checked. Redirects aren't
+/// followed.
+pub struct CallbackNotifier {
+    client: reqwest::r#async::Client,
+    allowlist: Arc<Allowlist>,
+}
+
+impl CallbackNotifier {
+    pub fn new(allowlist: Arc<Allowlist>) -> Self {
+        CallbackNotifier {
+            client: reqwest::r#async::Client::builder()
+                .redirect(RedirectPolicy::none())
+                .build()
+                .expect("the callback client should build. This is a bug!"),
+            allowlist,
+        }
+    }
+}
+
+/// Resolves the host of `url` on a thread of its own, as the resolver blocks.
+fn resolve(url: &Url) -> impl Future<Item = Vec<IpAddr>, Error = String> {
+    let (resolved, addrs) = oneshot::channel();
+    let host = url.host_str().unwrap_or_default().to_owned();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    thread::spawn(move || {
+        let _ = resolved.send(
+            (host.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr: SocketAddr| addr.ip()).collect())
+                .map_err(|e| format!("{}", e)),
+        );
+    });
+
+    addrs
+        .map_err(|_| "the resolver has stopped".to_owned())
+        .and_then(|res| res)
+}
+
+impl Notifier for CallbackNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        match event {
+            Event::Missed | Event::MissedOffline(_) | Event::Abandoned => (),
+            Event::Early(_)
+            | Event::Late(_)
+            | Event::Warning(_)
+            | Event::Irregular { .. }
+            | Event::CaughtUp(_) => return,
+        }
+
+        let callback = match &s.metadata.callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        if !self.allowlist.permits(callback) {
+            return warn!(
+                "failed to notify callback; not allowed, switch={}, url={}",
+                s.name, callback
+            );
+        }
+        let url = Url::parse(callback).expect("permitted callback should parse. This is a bug!");
+
+        let name = s.name.clone();
+        let client = self.client.clone();
+        let allowlist = Arc::clone(&self.allowlist);
+        let body = event_json(s, event);
+
+        spawn(resolve(&url).then(move |res| {
+            let ip = match res {
+                Ok(ips) => match ips.iter().find(|ip| !allowlist.permits_addr(**ip)) {
+                    Some(ip) => Err(format!("not allowed to post to {}", ip)),
+                    None => ips
+                        .first()
+                        .cloned()
+                        .ok_or_else(|| "no addresses".to_owned()),
+                },
+                Err(e) => Err(e),
+            };
+            let ip = match ip {
+                Ok(ip) => ip,
+                Err(e) => {
+                    warn!(
+                        "failed to notify callback; switch={}, url={}, {}",
+                        name, url, e
+                    );
+                    return Ok(());
+                }
+            };
+
+            // HTTPS checks the name against the certificate so it has to connect by name.
+            let mut to = url.clone();
+            let request = if url.scheme() == "http" && to.set_ip_host(ip).is_ok() {
+                let host = match url.port() {
+                    Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+                    None => url.host_str().unwrap_or_default().to_owned(),
+                };
+                client.post(to.as_str()).header(HOST, host)
+            } else {
+                client.post(url.as_str())
+            };
+
+            send("callback", request.json(&body));
+            Ok(())
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(allowed: &[&str]) -> Allowlist {
+        Allowlist::new(allowed.iter().map(|v| parse_allow(v).unwrap()).collect())
+    }
+
+    #[test]
+    fn parses_hosts_and_networks() {
+        match parse_allow("Hooks.Example.net").unwrap() {
+            Allow::Host(host) => assert_eq!(host, "hooks.example.net"),
+            allow => panic!("{:?}", allow),
+        }
+        match parse_allow("10.1.0.0/16").unwrap() {
+            Allow::Network(_) => (),
+            allow => panic!("{:?}", allow),
+        }
+        for v in &["", "hooks.example.net/16", "https://hooks.example.net"] {
+            assert!(parse_allow(v).is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn refuses_every_callback_without_an_allowlist() {
+        let allowlist = allowlist(&[]);
+        assert!(!allowlist.permits("https://hooks.example.net/backup"));
+        assert!(!allowlist.permits("http://203.0.113.1/backup"));
+    }
+
+    #[test]
+    fn permits_only_allowed_hosts() {
+        let allowlist = allowlist(&["hooks.example.net"]);
+        assert!(allowlist.permits("https://hooks.example.net/backup"));
+        assert!(allowlist.permits("http://HOOKS.example.net:8080/backup"));
+        assert!(!allowlist.permits("https://other.example.net/backup"));
+        assert!(!allowlist.permits("https://hooks.example.net.evil.example/backup"));
+        assert!(!allowlist.permits("ftp://hooks.example.net/backup"));
+        assert!(!allowlist.permits("hooks.example.net"));
+    }
+
+    #[test]
+    fn refuses_internal_addresses_unless_a_network_has_them() {
+        let hosts = allowlist(&["hooks.example.net"]);
+        for ip in &[
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!hosts.permits_addr(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(hosts.permits_addr("203.0.113.1".parse().unwrap()));
+        assert!(hosts.permits_addr("2001:db8::1".parse().unwrap()));
+
+        let networks = allowlist(&["10.1.0.0/16"]);
+        assert!(networks.permits("http://10.1.2.3/backup"));
+        assert!(!networks.permits("http://10.2.0.1/backup"));
+        assert!(!networks.permits("http://203.0.113.1/backup"));
+        assert!(!networks.permits("http://[::1]/backup"));
+    }
+}
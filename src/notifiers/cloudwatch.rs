@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::Future;
+use log::{info, warn};
+use parking_lot::Mutex;
+use rusoto_cloudwatch::{CloudWatch, CloudWatchClient, Dimension, MetricDatum, PutMetricDataInput};
+use rusoto_core::Region;
+use rusoto_logs::{CloudWatchLogs, CloudWatchLogsClient, InputLogEvent, PutLogEventsRequest};
+
+use crate::notifiers::{event_json, spawn, Event, Notifier};
+use crate::Switch;
+
+/// Where events are additionally written as CloudWatch Logs events.
+pub struct LogStream {
+    pub group: String,
+    pub stream: String,
+}
+
+/// Emits a `Missed` or `Early` count metric with a `Switch` dimension for every event, and
+/// optionally writes the event to a CloudWatch Logs stream. Credentials are found the same way the
+/// AWS CLI finds them.
+pub struct CloudWatchNotifier {
+    metrics: CloudWatchClient,
+    namespace: String,
+    logs: Option<(CloudWatchLogsClient, LogStream)>,
+    sequence_token: Arc<Mutex<Option<String>>>,
+}
+
+impl CloudWatchNotifier {
+    pub fn new(region: Region, namespace: &str, log_stream: Option<LogStream>) -> Self {
+        CloudWatchNotifier {
+            metrics: CloudWatchClient::new(region.clone()),
+            namespace: namespace.to_owned(),
+            logs: log_stream.map(|ls| (CloudWatchLogsClient::new(region), ls)),
+            sequence_token: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Notifier for CloudWatchNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let datum = MetricDatum {
+            metric_name: match event {
+                Event::Missed => "Missed",
+                Event::MissedOffline(_) => "MissedOffline",
+                Event::CaughtUp(_) => "CaughtUp",
+                Event::Early(_) => "Early",
+                Event::Late(_) => "Late",
+                Event::Warning(_) => "Warning",
+                Event::Abandoned => "Abandoned",
+                Event::Irregular { .. } => "Irregular",
+            }
+            .to_owned(),
+            dimensions: Some(vec![Dimension {
+                name: "Switch".to_owned(),
+                value: s.name.clone(),
+            }]),
+            unit: Some("Count".to_owned()),
+            value: Some(1.0),
+            ..Default::default()
+        };
+
+        spawn(
+            self.metrics
+                .put_metric_data(PutMetricDataInput {
+                    metric_data: vec![datum],
+                    namespace: self.namespace.clone(),
+                })
+                .map(|_| info!("notified cloudwatch"))
+                .map_err(|e| warn!("failed to put cloudwatch metric; {}", e)),
+        );
+
+        if let Some((client, ls)) = &self.logs {
+            let sequence_token = Arc::clone(&self.sequence_token);
+
+            let request = PutLogEventsRequest {
+                log_events: vec![InputLogEvent {
+                    message: event_json(s, event).to_string(),
+                    timestamp: Utc::now().timestamp_millis(),
+                }],
+                log_group_name: ls.group.clone(),
+                log_stream_name: ls.stream.clone(),
+                sequence_token: self.sequence_token.lock().clone(),
+            };
+
+            spawn(
+                client
+                    .put_log_events(request)
+                    .map(move |res| *sequence_token.lock() = res.next_sequence_token)
+                    .map_err(|e| warn!("failed to put cloudwatch log event; {}", e)),
+            );
+        }
+    }
+}
@@ -5,7 +5,8 @@ use log::{info, warn};
 use tokio::prelude::*;
 use tokio_process::CommandExt;
 
-use crate::notifiers::Notifier;
+use crate::notifiers::{labels, spawn, Event, Notifier};
+use crate::Switch;
 
 pub struct Command {
     pub cmd: Vec<String>,
@@ -20,16 +21,34 @@ impl Command {
 }
 
 impl Notifier for Command {
-    fn notify(&self, name: String, early: Option<u64>) {
+    fn notify(&self, s: &Switch, event: Event) {
         info!("running notify command: cmd={}", self.cmd.join(" "));
 
         let proc = StdCommand::new(&self.cmd[0])
             .args(self.cmd[1..].into_iter())
-            .env("CONDEMN_NAME", name)
-            .env("CONDEMN_EARLY", format!("{}", early.unwrap_or(0)))
+            .env("CONDEMN_NAME", &s.name)
+            .env("CONDEMN_EARLY", format!("{}", event.early().unwrap_or(0)))
+            .env("CONDEMN_LATE", format!("{}", event.late().unwrap_or(0)))
+            .env("CONDEMN_EVENT", event.name())
+            .env(
+                "CONDEMN_DESCRIPTION",
+                s.metadata.description.as_deref().unwrap_or(""),
+            )
+            .env("CONDEMN_OWNER", s.metadata.owner.as_deref().unwrap_or(""))
+            .env("CONDEMN_LABELS", labels(&s.metadata.labels, ","))
+            .env("CONDEMN_PRIORITY", s.metadata.priority.as_str())
+            .env(
+                "CONDEMN_MESSAGE",
+                s.metadata.message.as_deref().unwrap_or(""),
+            )
+            // A payload is whatever the job sent, and a NUL would keep the command from running.
+            .env(
+                "CONDEMN_PAYLOAD",
+                s.payload.as_deref().unwrap_or("").replace('\0', ""),
+            )
             .spawn_async();
 
-        tokio::spawn(match proc {
+        spawn(match proc {
             Ok(f) => Either::A(
                 f.map(|status| info!("command exited with status {}", status))
                     .map_err(|e| warn!("failed to wait for exit: {}", e)),
@@ -0,0 +1,45 @@
+use chrono::Utc;
+
+use crate::notifiers::{event_json, send, Event, Notifier};
+use crate::Switch;
+
+/// Indexes every event as a document in a daily index, `<index>-YYYY.MM.DD`, of Elasticsearch or
+/// OpenSearch.
+pub struct ElasticsearchNotifier {
+    client: reqwest::r#async::Client,
+    base_url: String,
+    index: String,
+    credentials: Option<(String, Option<String>)>,
+}
+
+impl ElasticsearchNotifier {
+    pub fn new(base_url: &str, index: &str, credentials: Option<(String, Option<String>)>) -> Self {
+        ElasticsearchNotifier {
+            client: reqwest::r#async::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            index: index.to_owned(),
+            credentials,
+        }
+    }
+}
+
+impl Notifier for ElasticsearchNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let url = format!(
+            "{}/{}-{}/_doc",
+            self.base_url,
+            self.index,
+            Utc::now().format("%Y.%m.%d")
+        );
+
+        let request = self.client.post(&url).json(&event_json(s, event));
+
+        send(
+            "elasticsearch",
+            match &self.credentials {
+                None => request,
+                Some((user, password)) => request.basic_auth(user, password.as_ref()),
+            },
+        );
+    }
+}
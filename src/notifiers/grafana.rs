@@ -0,0 +1,54 @@
+use chrono::Utc;
+
+use crate::notifiers::{event_message, send, Event, Notifier};
+use crate::Switch;
+
+/// Writes an annotation through the Grafana HTTP API, tagged with the switch name, event type,
+/// owner and labels, so misses show up on dashboards.
+pub struct GrafanaNotifier {
+    client: reqwest::r#async::Client,
+    url: String,
+    token: String,
+}
+
+impl GrafanaNotifier {
+    pub fn new(base_url: &str, token: &str) -> Self {
+        GrafanaNotifier {
+            client: reqwest::r#async::Client::new(),
+            url: format!("{}/api/annotations", base_url.trim_end_matches('/')),
+            token: token.to_owned(),
+        }
+    }
+}
+
+impl Notifier for GrafanaNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let mut tags = vec![
+            "condemn".to_owned(),
+            format!("switch:{}", s.name),
+            event.name().to_owned(),
+        ];
+        tags.extend(s.metadata.owner.iter().map(|o| format!("owner:{}", o)));
+        tags.push(format!("priority:{}", s.metadata.priority.as_str()));
+        tags.extend(
+            s.metadata
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}:{}", k, v)),
+        );
+
+        let body = serde_json::json!({
+            "time": Utc::now().timestamp_millis(),
+            "tags": tags,
+            "text": event_message(s, event),
+        });
+
+        send(
+            "grafana",
+            self.client
+                .post(&self.url)
+                .bearer_auth(&self.token)
+                .json(&body),
+        );
+    }
+}
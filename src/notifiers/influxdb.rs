@@ -0,0 +1,82 @@
+use chrono::Utc;
+
+use crate::notifiers::{send, Event, Notifier};
+use crate::Switch;
+
+pub enum InfluxAuth {
+    None,
+    /// InfluxDB 1.x username and password.
+    Basic(String, Option<String>),
+    /// InfluxDB 2.x API token.
+    Token(String),
+}
+
+/// Writes every event as a point in InfluxDB line protocol to the `condemn` measurement, tagged
+/// with the switch and event type.
+pub struct InfluxNotifier {
+    client: reqwest::r#async::Client,
+    write_url: String,
+    auth: InfluxAuth,
+}
+
+impl InfluxNotifier {
+    /// For the 1.x `/write` API.
+    pub fn v1(base_url: &str, db: &str, auth: InfluxAuth) -> Self {
+        InfluxNotifier {
+            client: reqwest::r#async::Client::new(),
+            write_url: format!(
+                "{}/write?db={}&precision=s",
+                base_url.trim_end_matches('/'),
+                db
+            ),
+            auth,
+        }
+    }
+
+    /// For the 2.x `/api/v2/write` API.
+    pub fn v2(base_url: &str, org: &str, bucket: &str, token: &str) -> Self {
+        InfluxNotifier {
+            client: reqwest::r#async::Client::new(),
+            write_url: format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=s",
+                base_url.trim_end_matches('/'),
+                org,
+                bucket
+            ),
+            auth: InfluxAuth::Token(token.to_owned()),
+        }
+    }
+}
+
+fn escape_tag(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+impl Notifier for InfluxNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let line = format!(
+            "condemn,switch={},event={} early={}i,late={}i {}",
+            escape_tag(&s.name),
+            event.name(),
+            event.early().unwrap_or(0),
+            event.late().unwrap_or(0),
+            Utc::now().timestamp()
+        );
+
+        let request = self.client.post(&self.write_url).body(line);
+
+        send(
+            "influxdb",
+            match &self.auth {
+                InfluxAuth::None => request,
+                InfluxAuth::Basic(user, password) => request.basic_auth(user, password.as_ref()),
+                InfluxAuth::Token(token) => {
+                    request.header("Authorization", format!("Token {}", token))
+                }
+            },
+        );
+    }
+}
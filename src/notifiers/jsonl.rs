@@ -0,0 +1,101 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use log::warn;
+use parking_lot::Mutex;
+
+use crate::notifiers::{event_json, Event, Notifier};
+use crate::Switch;
+
+/// Appends every event as a single line of JSON to a file. The file is rotated once it grows past
+/// `max_size` bytes, keeping up to `keep` old files alongside it as `<file>.1` (newest) through
+/// `<file>.<keep>` (oldest).
+///
+/// The file is written by a thread of its own, so that a slow disk holds up neither check-ins nor
+/// the watcher. The thread ends once the notifier is dropped and it has written what it was sent.
+pub struct JsonlNotifier {
+    filename: PathBuf,
+    lines: Mutex<Sender<Vec<u8>>>,
+}
+
+/// The file and its rotated copies, owned by the writer thread.
+struct Log {
+    filename: PathBuf,
+    max_size: u64,
+    keep: usize,
+}
+
+impl JsonlNotifier {
+    pub fn new<P: AsRef<Path>>(filename: P, max_size: u64, keep: usize) -> Self {
+        let log = Log {
+            filename: filename.as_ref().to_path_buf(),
+            max_size,
+            keep,
+        };
+        let (lines, received) = channel::<Vec<u8>>();
+
+        thread::spawn(move || {
+            for line in received {
+                if let Err(e) = log.append(&line) {
+                    warn!("failed to write event log '{:?}'; {}", log.filename, e);
+                }
+            }
+        });
+
+        Self {
+            filename: filename.as_ref().to_path_buf(),
+            lines: Mutex::new(lines),
+        }
+    }
+}
+
+impl Log {
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.filename.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> Result<(), std::io::Error> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.filename);
+        }
+
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+
+        fs::rename(&self.filename, self.rotated(1))
+    }
+
+    fn append(&self, line: &[u8]) -> Result<(), std::io::Error> {
+        let size = fs::metadata(&self.filename).map(|m| m.len()).unwrap_or(0);
+
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.filename)?
+            .write_all(line)
+    }
+}
+
+impl Notifier for JsonlNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let mut line = event_json(s, event).to_string().into_bytes();
+        line.push(b'\n');
+
+        if self.lines.lock().send(line).is_err() {
+            warn!("event log writer has stopped; file={:?}", self.filename);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::notifiers::{event_message, send, Event, Notifier};
+use crate::Switch;
+
+const LINE_NOTIFY_URL: &str = "https://notify-api.line.me/api/notify";
+
+/// Sends a message through LINE Notify with a personal or group access token.
+pub struct LineNotifier {
+    client: reqwest::r#async::Client,
+    token: String,
+}
+
+impl LineNotifier {
+    pub fn new(token: &str) -> Self {
+        LineNotifier {
+            client: reqwest::r#async::Client::new(),
+            token: token.to_owned(),
+        }
+    }
+}
+
+impl Notifier for LineNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        send(
+            "line",
+            self.client
+                .post(LINE_NOTIFY_URL)
+                .bearer_auth(&self.token)
+                .form(&[("message", event_message(s, event))]),
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use chrono::Utc;
+
+use crate::notifiers::{event_json, send, Event, Notifier};
+use crate::Switch;
+
+/// Pushes every event as a JSON log line to Loki, labelled with the switch and event type.
+pub struct LokiNotifier {
+    client: reqwest::r#async::Client,
+    push_url: String,
+    credentials: Option<(String, Option<String>)>,
+}
+
+impl LokiNotifier {
+    pub fn new(base_url: &str, credentials: Option<(String, Option<String>)>) -> Self {
+        LokiNotifier {
+            client: reqwest::r#async::Client::new(),
+            push_url: format!("{}/loki/api/v1/push", base_url.trim_end_matches('/')),
+            credentials,
+        }
+    }
+}
+
+impl Notifier for LokiNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let nanos = Utc::now().timestamp_nanos().to_string();
+
+        let body = serde_json::json!({
+            "streams": [{
+                "stream": { "job": "condemn", "switch": s.name, "event": event.name() },
+                "values": [[nanos, event_json(s, event).to_string()]],
+            }],
+        });
+
+        let request = self.client.post(&self.push_url).json(&body);
+
+        send(
+            "loki",
+            match &self.credentials {
+                None => request,
+                Some((user, password)) => request.basic_auth(user, password.as_ref()),
+            },
+        );
+    }
+}
@@ -1,13 +1,250 @@
-use log::info;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::Utc;
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::{Future, Stream};
+use log::{info, warn};
+use parking_lot::RwLock;
+use tokio::timer::Delay;
+
+use crate::schedule::Schedule;
+use crate::tunnel::TlsConfig;
+use crate::{access, auth, config};
+use crate::{Priority, Switch};
+
+pub mod calendar;
+pub mod callback;
+pub mod cloudwatch;
 pub mod command;
+pub mod elasticsearch;
+pub mod grafana;
+pub mod influxdb;
+pub mod jsonl;
+pub mod line;
+pub mod loki;
+pub mod redis;
 pub mod sentry;
+pub mod service_url;
+pub mod signal;
+pub mod slack;
+pub mod smtp;
+pub mod telegram;
+pub mod webex;
 
+pub use self::callback::CallbackNotifier;
+pub use self::cloudwatch::{CloudWatchNotifier, LogStream};
+pub use self::elasticsearch::ElasticsearchNotifier;
+pub use self::grafana::GrafanaNotifier;
+pub use self::influxdb::{InfluxAuth, InfluxNotifier};
+pub use self::jsonl::JsonlNotifier;
+pub use self::line::LineNotifier;
+pub use self::loki::LokiNotifier;
+pub use self::redis::RedisNotifier;
 pub use self::sentry::SentryNotifier;
+pub use self::service_url::{check_url, from_url};
+pub use self::signal::{SignalApi, SignalNotifier};
+pub use self::slack::SlackNotifier;
+pub use self::smtp::{SmtpNotifier, SmtpSecurity, SmtpServer};
+pub use self::telegram::TelegramNotifier;
+pub use self::webex::WebexNotifier;
 pub use command::Command as CommandNotifier;
 
+/// What a notification is about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The switch missed its deadline.
+    Missed,
+    /// The switch missed its deadline this many seconds ago, while condemn was down.
+    MissedOffline(u64),
+    /// condemn has started after being down and notified of this many switches that missed their
+    /// deadlines meanwhile.
+    CaughtUp(u64),
+    /// The switch checked in this many seconds before its window.
+    Early(u64),
+    /// The switch checked in this many seconds after its deadline, but within its late tolerance.
+    Late(u64),
+    /// The switch hasn't checked in and its deadline is this many seconds away.
+    Warning(u64),
+    /// The switch missed so many times in a row without checking in that it has been removed.
+    Abandoned,
+    /// The switch checked in `interval` seconds after its last check-in, far from the `usual`.
+    Irregular { interval: u64, usual: u64 },
+}
+
+impl Event {
+    /// The name of the event in machine-readable notifications.
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Missed => "missed",
+            Event::MissedOffline(_) => "missed_offline",
+            Event::CaughtUp(_) => "caught_up",
+            Event::Early(_) => "early",
+            Event::Late(_) => "late",
+            Event::Warning(_) => "warning",
+            Event::Abandoned => "abandoned",
+            Event::Irregular { .. } => "irregular",
+        }
+    }
+
+    /// The seconds the switch checked in before its window, if it was early.
+    pub fn early(self) -> Option<u64> {
+        match self {
+            Event::Early(secs) => Some(secs),
+            _ => None,
+        }
+    }
+
+    /// The seconds the switch checked in after its deadline, if it was late but tolerated.
+    pub fn late(self) -> Option<u64> {
+        match self {
+            Event::Late(secs) => Some(secs),
+            _ => None,
+        }
+    }
+}
+
 pub trait Notifier {
-    fn notify(&self, name: String, early: Option<u64>);
+    fn notify(&self, s: &Switch, event: Event);
+}
+
+impl<N: Notifier + ?Sized> Notifier for Arc<N> {
+    fn notify(&self, s: &Switch, event: Event) {
+        (**self).notify(s, event);
+    }
+}
+
+/// The structured form of a notification used by notifiers that emit machine-readable events.
+pub fn event_json(s: &Switch, event: Event) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "switch": s.name,
+        "event": event.name(),
+        "early": event.early(),
+        "late": event.late(),
+        "description": s.metadata.description,
+        "owner": s.metadata.owner,
+        "labels": s.metadata.labels,
+        "message": s.metadata.message,
+        "priority": s.metadata.priority,
+        "payload": s.payload,
+        "timestamp": Utc::now().to_rfc3339(),
+        "request_id": access::request_id(),
+    });
+    match event {
+        Event::Irregular { interval, usual } => {
+            json["interval"] = interval.into();
+            json["usual_interval"] = usual.into();
+        }
+        Event::MissedOffline(secs) => json["missed_by"] = secs.into(),
+        Event::Warning(secs) => json["remaining"] = secs.into(),
+        Event::CaughtUp(missed) => json["missed"] = missed.into(),
+        _ => (),
+    }
+
+    json
+}
+
+/// The human readable form of a notification used by chat-like notifiers, followed by whatever
+/// metadata the switch has on lines of their own. A miss is told with the switch's own message if
+/// it has one.
+pub fn event_message(s: &Switch, event: Event) -> String {
+    let mut message = match (event, &s.metadata.message) {
+        (Event::Early(secs), _) => {
+            format!("Switch `{}` checked in early by {} seconds", s.name, secs)
+        }
+        (Event::Late(secs), _) => format!(
+            "Switch `{}` checked in late by {} seconds, within its late tolerance.",
+            s.name, secs
+        ),
+        (Event::Warning(secs), _) => format!(
+            "Switch `{}` has not checked in and will expire in {}.",
+            s.name,
+            humantime::format_duration(Duration::from_secs(secs))
+        ),
+        (Event::Abandoned, _) => format!(
+            "Switch `{}` missed {} times in a row without checking in and has been removed.",
+            s.name, s.misses
+        ),
+        (Event::Irregular { interval, usual }, _) => format!(
+            "Switch `{}` checked in {} seconds after its last check-in, when it usually does after {}.",
+            s.name, interval, usual
+        ),
+        (Event::Missed, Some(message)) => message.clone(),
+        (Event::Missed, None) => format!("Switch `{}` failed to make its deadline.", s.name),
+        (Event::MissedOffline(secs), message) => format!(
+            "{}Switch `{}` missed its deadline by {} while monitoring was offline.",
+            message.as_ref().map_or(String::new(), |m| format!("{}\n", m)),
+            s.name,
+            humantime::format_duration(Duration::from_secs(secs))
+        ),
+        (Event::CaughtUp(missed), _) => format!(
+            "condemn is back after being offline. {} switches missed their deadlines meanwhile.",
+            missed
+        ),
+    };
+
+    if let Some(description) = &s.metadata.description {
+        message.push_str(&format!("\n{}", description));
+    }
+    if let Some(owner) = &s.metadata.owner {
+        message.push_str(&format!("\nOwner: {}", owner));
+    }
+    if s.metadata.priority != Priority::Normal {
+        message.push_str(&format!("\nPriority: {}", s.metadata.priority.as_str()));
+    }
+    if !s.metadata.labels.is_empty() {
+        message.push_str(&format!("\nLabels: {}", labels(&s.metadata.labels, ", ")));
+    }
+    if let Some(payload) = &s.payload {
+        message.push_str(&format!("\nLast check-in: {}", payload));
+    }
+
+    message
+}
+
+/// Labels as `key=value` pairs joined with `sep`.
+pub fn labels(labels: &BTreeMap<String, String>, sep: &str) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+/// How many notifications `spawn` has running.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs a notification in the background, counted by `pending()` until it is done so that
+/// shutting down can wait for it.
+pub fn spawn<F: 'static + Future<Item = (), Error = ()> + Send>(f: F) {
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    tokio::spawn(f.then(|res| {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+        res
+    }));
+}
+
+/// How many notifications are still being sent.
+pub fn pending() -> usize {
+    PENDING.load(Ordering::SeqCst)
+}
+
+/// Sends a request for an HTTP based notifier in the background, logging the outcome.
+pub fn send(notifier: &'static str, request: reqwest::r#async::RequestBuilder) {
+    spawn(
+        request
+            .send()
+            .map(move |res| {
+                if res.status().is_success() {
+                    info!("notified {}; status={}", notifier, res.status());
+                } else {
+                    warn!("failed to notify {}; status={}", notifier, res.status());
+                }
+            })
+            .map_err(move |e| warn!("failed to notify {}; {}", notifier, e)),
+    );
 }
 
 pub struct AggregateNotifier<'a> {
@@ -22,23 +259,300 @@ impl<'a> AggregateNotifier<'a> {
     pub fn push<T: 'a + Notifier + Send + Sync>(&mut self, n: T) {
         self.notifiers.push(Box::new(n));
     }
+
+    pub fn push_boxed(&mut self, n: Box<'a + Notifier + Send + Sync>) {
+        self.notifiers.push(n);
+    }
 }
 
 impl<'a> Notifier for AggregateNotifier<'a> {
-    fn notify(&self, name: String, early: Option<u64>) {
+    fn notify(&self, s: &Switch, event: Event) {
         for n in &self.notifiers {
-            n.notify(name.clone(), early);
+            n.notify(s, event);
         }
     }
 }
 
+/// Sends the notifications for switches in a namespace with notifiers of its own to those, and
+/// the rest to `default`.
+pub struct NamespaceNotifier<'a> {
+    default: AggregateNotifier<'a>,
+    namespaces: HashMap<String, AggregateNotifier<'a>>,
+    callbacks: Arc<callback::Allowlist>,
+}
+
+impl<'a> NamespaceNotifier<'a> {
+    pub fn new(default: AggregateNotifier<'a>, callbacks: Arc<callback::Allowlist>) -> Self {
+        Self {
+            default,
+            namespaces: HashMap::new(),
+            callbacks,
+        }
+    }
+
+    pub fn push_boxed(&mut self, namespace: &str, n: Box<'a + Notifier + Send + Sync>) {
+        let callbacks = &self.callbacks;
+        self.namespaces
+            .entry(namespace.to_owned())
+            .or_insert_with(|| {
+                let mut notifier = AggregateNotifier::new();
+                notifier.push(LogNotifier {});
+                notifier.push(CallbackNotifier::new(Arc::clone(callbacks)));
+                notifier
+            })
+            .push_boxed(n);
+    }
+}
+
+impl<'a> Notifier for NamespaceNotifier<'a> {
+    fn notify(&self, s: &Switch, event: Event) {
+        auth::namespace_of(&s.name)
+            .and_then(|namespace| self.namespaces.get(namespace))
+            .unwrap_or(&self.default)
+            .notify(s, event);
+    }
+}
+
+/// Splits `namespace=url` at the first `=`.
+pub fn parse_namespace_url(v: &str) -> Result<(&str, &str), String> {
+    match v.find('=') {
+        Some(i) if i > 0 && !v[..i].contains('/') => Ok((&v[..i], &v[i + 1..])),
+        _ => Err("should be namespace=url".to_owned()),
+    }
+}
+
+/// Where the notifiers come from, kept so that they can be loaded again by `Reloadable`.
+pub struct Config {
+    /// The `--notify` kinds, which are only read at start, but for `command`.
+    pub kinds: Vec<Arc<Notifier + Send + Sync>>,
+    /// The `--notify-command` of `--notify command`.
+    pub command: Option<String>,
+    pub urls: Vec<String>,
+    pub namespace_urls: Vec<(String, String)>,
+    /// When switches without a calendar of their own may notify, from `--calendar`.
+    pub calendar: Option<Schedule>,
+    /// A file of notifier URLs, one per line as `url` or `namespace=url`. It is read again on
+    /// every load.
+    pub file: Option<String>,
+    /// For `rediss://` URLs, the same as the `redis` store's.
+    pub tls: TlsConfig,
+    /// Where switches' callbacks may be posted to.
+    pub callbacks: Arc<callback::Allowlist>,
+    /// A `--config` file, whose notifier URLs are notified as well and whose `notify-command` and
+    /// `calendar` are used instead of those of the command line. It is read again on every load.
+    pub config: Option<String>,
+}
+
+impl Config {
+    /// The notifiers and the calendar of switches without one as configured now.
+    pub fn load(&self) -> Result<(NamespaceNotifier<'static>, Option<Schedule>), String> {
+        let options = config::read(self.config.as_deref())?;
+
+        let mut default = AggregateNotifier::new();
+        default.push(LogNotifier {});
+        default.push(CallbackNotifier::new(Arc::clone(&self.callbacks)));
+        for n in &self.kinds {
+            default.push(Arc::clone(n));
+        }
+        if let Some(command) = options
+            .value_of("notify-command")
+            .or(self.command.as_deref())
+        {
+            default.push(CommandNotifier::new(command));
+        }
+        for url in self
+            .urls
+            .iter()
+            .map(String::as_str)
+            .chain(options.values_of("notify-url"))
+        {
+            default.push_boxed(from_url(url, &self.tls)?);
+        }
+
+        let mut namespaced = vec![];
+        for (namespace, url) in &self.namespace_urls {
+            namespaced.push((namespace.clone(), from_url(url, &self.tls)?));
+        }
+        for v in options.values_of("namespace-notify-url") {
+            let (namespace, url) = parse_namespace_url(v)?;
+            namespaced.push((namespace.to_owned(), from_url(url, &self.tls)?));
+        }
+
+        let calendar = match options.value_of("calendar") {
+            Some(calendar) => Some(calendar.parse()?),
+            None => self.calendar.clone(),
+        };
+
+        if let Some(file) = &self.file {
+            let contents = std::fs::read_to_string(file).map_err(|e| format!("{}; {}", file, e))?;
+            let lines = contents
+                .lines()
+                .map(str::trim)
+                .enumerate()
+                .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+            for (i, line) in lines {
+                let (namespace, url) = match parse_namespace_url(line) {
+                    Ok((namespace, url)) => (Some(namespace), url),
+                    Err(_) => (None, line),
+                };
+                let n = from_url(url, &self.tls)
+                    .map_err(|e| format!("{} line {}; {}", file, i + 1, e))?;
+                match namespace {
+                    Some(namespace) => namespaced.push((namespace.to_owned(), n)),
+                    None => default.push_boxed(n),
+                }
+            }
+        }
+
+        let mut notifier = NamespaceNotifier::new(default, Arc::clone(&self.callbacks));
+        for (namespace, n) in namespaced {
+            notifier.push_boxed(&namespace, n);
+        }
+
+        Ok((notifier, calendar))
+    }
+}
+
+/// Notifiers that can be swapped for newly loaded ones while running, so that they can be
+/// changed without restarting and losing the switches of the memory store. Notifications of
+/// switches whose calendar is closed, or `--calendar` for those without one, are held here until
+/// it opens, and may be paced so that many switches missing at once don't send all their
+/// notifications at once.
+pub struct Reloadable {
+    config: Config,
+    current: Arc<RwLock<Arc<NamespaceNotifier<'static>>>>,
+    calendar: RwLock<Option<Schedule>>,
+    held: calendar::Held,
+    paced: Option<Paced>,
+}
+
+/// Notifications waiting their turn to be sent.
+struct Paced {
+    queue: UnboundedSender<(Switch, Event)>,
+    queued: Arc<AtomicUsize>,
+    per_second: u32,
+}
+
+impl Reloadable {
+    pub fn new(config: Config) -> Result<Self, String> {
+        let (notifier, calendar) = config.load()?;
+        Ok(Self {
+            config,
+            current: Arc::new(RwLock::new(Arc::new(notifier))),
+            calendar: RwLock::new(calendar),
+            held: calendar::Held::new(),
+            paced: None,
+        })
+    }
+
+    /// Sends notifications no faster than `per_second` from now on, queueing those that come
+    /// quicker. The returned future sends them and is to be run for as long as notifications are.
+    pub fn pace(&mut self, per_second: u32) -> impl Future<Item = (), Error = ()> {
+        let (queue, waiting) = unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        self.paced = Some(Paced {
+            queue,
+            queued: Arc::clone(&queued),
+            per_second,
+        });
+
+        let current = Arc::clone(&self.current);
+        let gap = Duration::from_secs(1) / per_second;
+
+        waiting.for_each(move |(s, event): (Switch, Event)| {
+            let notifier = Arc::clone(&current.read());
+            notifier.notify(&s, event);
+            queued.fetch_sub(1, Ordering::SeqCst);
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+
+            Delay::new(Instant::now() + gap).map_err(|e| warn!("pacing failed; {}", e))
+        })
+    }
+
+    /// Sends the notification now or, when paced, once its turn comes.
+    fn send(&self, s: &Switch, event: Event) {
+        let paced = match &self.paced {
+            Some(paced) => paced,
+            None => {
+                let current = Arc::clone(&self.current.read());
+                return current.notify(s, event);
+            }
+        };
+
+        // Counted as pending so that shutting down waits for the queue to empty.
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        let queued = paced.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        if queued == paced.per_second as usize {
+            warn!(
+                "notifying faster than --notify-rate allows, pacing; queued={}, per_second={}",
+                queued, paced.per_second
+            );
+        }
+
+        if paced.queue.unbounded_send((s.clone(), event)).is_err() {
+            warn!("notification pacing stopped, dropping; name={}", s.name);
+            paced.queued.fetch_sub(1, Ordering::SeqCst);
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Loads the notifiers and calendar again and uses them from now on. If they can't be loaded
+    /// the ones there were are kept. Notifications already held stay held until they were to be
+    /// sent.
+    pub fn reload(&self) -> Result<(), String> {
+        let (notifier, calendar) = self.config.load()?;
+        *self.current.write() = Arc::new(notifier);
+        *self.calendar.write() = calendar;
+        info!("notifiers reloaded");
+        Ok(())
+    }
+
+    /// Sends the notifications held by switches' calendars that have opened since.
+    pub fn release_held(&self) {
+        self.held.release(&Unheld(self), Utc::now());
+    }
+}
+
+impl Notifier for Reloadable {
+    fn notify(&self, s: &Switch, event: Event) {
+        let calendar = self.calendar.read().clone();
+        if self.held.hold(s, event, calendar.as_ref(), Utc::now()) {
+            return;
+        }
+
+        self.send(s, event);
+    }
+}
+
+/// Sends notifications that have already been held.
+struct Unheld<'a>(&'a Reloadable);
+
+impl<'a> Notifier for Unheld<'a> {
+    fn notify(&self, s: &Switch, event: Event) {
+        self.0.send(s, event);
+    }
+}
+
 pub struct LogNotifier {}
 
 impl Notifier for LogNotifier {
-    fn notify(&self, name: String, early: Option<u64>) {
-        match early {
-            Some(secs) => info!("notify early: name={}, early={}s", name, secs),
-            None => info!("notify late: name={}", name),
+    fn notify(&self, s: &Switch, event: Event) {
+        match event {
+            Event::Missed => info!("notify late: name={}", s.name),
+            Event::MissedOffline(secs) => info!(
+                "notify late while offline: name={}, missed_by={}s",
+                s.name, secs
+            ),
+            Event::CaughtUp(missed) => info!("notify caught up: missed={}", missed),
+            Event::Early(secs) => info!("notify early: name={}, early={}s", s.name, secs),
+            Event::Late(secs) => info!("notify tolerated late: name={}, late={}s", s.name, secs),
+            Event::Warning(secs) => info!("notify warning: name={}, remaining={}s", s.name, secs),
+            Event::Abandoned => info!("notify abandoned: name={}", s.name),
+            Event::Irregular { interval, usual } => info!(
+                "notify irregular: name={}, interval={}s, usual={}s",
+                s.name, interval, usual
+            ),
         }
     }
 }
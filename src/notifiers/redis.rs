@@ -0,0 +1,49 @@
+use futures::Future;
+use log::{info, warn};
+
+use crate::notifiers::{event_json, spawn, Event, Notifier};
+use crate::stores::Credentials;
+use crate::Switch;
+
+/// Publishes every event as JSON to a Redis channel so that anything subscribed to it can react
+/// to missed deadlines in real time.
+pub struct RedisNotifier {
+    client: redis::Client,
+    credentials: Credentials,
+    channel: String,
+}
+
+impl RedisNotifier {
+    pub fn new(url: &str, credentials: Credentials, channel: &str) -> Self {
+        RedisNotifier {
+            client: redis::Client::open(url).unwrap(),
+            credentials,
+            channel: channel.to_owned(),
+        }
+    }
+}
+
+impl Notifier for RedisNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let mut publish = redis::cmd("PUBLISH");
+        publish.arg(&self.channel);
+        publish.arg(event_json(s, event).to_string());
+
+        let channel = self.channel.clone();
+        let credentials = self.credentials.clone();
+
+        spawn(
+            self.client
+                .get_async_connection()
+                .and_then(move |conn| credentials.authenticate(conn))
+                .and_then(move |conn| publish.query_async::<_, i64>(conn))
+                .map(move |(_, receivers)| {
+                    info!(
+                        "published to redis; channel={}, receivers={}",
+                        channel, receivers
+                    )
+                })
+                .map_err(|e| warn!("failed to publish to redis; {:?}", e)),
+        );
+    }
+}
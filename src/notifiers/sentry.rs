@@ -1,11 +1,11 @@
 use std::borrow::Cow;
-use std::collections::BTreeMap;
 use std::time::Duration;
 
 use log::{error, info};
 
-use crate::notifiers::Notifier;
-use sentry::protocol::Event;
+use crate::notifiers::{event_message, Event, Notifier};
+use crate::{Priority, Switch};
+use sentry::protocol::{self, Level};
 
 pub struct SentryNotifier {
     dsn: String,
@@ -20,23 +20,44 @@ impl SentryNotifier {
 }
 
 impl Notifier for SentryNotifier {
-    fn notify(&self, name: String, early: Option<u64>) {
-        let mut tags = BTreeMap::new();
-        tags.insert("switch".to_owned(), name.clone());
+    fn notify(&self, s: &Switch, event: Event) {
+        let mut tags = s.metadata.labels.clone();
+        tags.insert("switch".to_owned(), s.name.clone());
+        if let Some(owner) = &s.metadata.owner {
+            tags.insert("owner".to_owned(), owner.clone());
+        }
+        tags.insert(
+            "priority".to_owned(),
+            s.metadata.priority.as_str().to_owned(),
+        );
 
-        let fp = format!("{}={}", name, early.map_or_else(|| "FAIL", |_| "EARLY"));
+        let fp = format!(
+            "{}={}",
+            s.name,
+            match event {
+                Event::Missed | Event::MissedOffline(_) => "FAIL",
+                Event::CaughtUp(_) => "CAUGHT_UP",
+                Event::Early(_) => "EARLY",
+                Event::Late(_) => "LATE",
+                Event::Warning(_) => "WARNING",
+                Event::Abandoned => "ABANDONED",
+                Event::Irregular { .. } => "IRREGULAR",
+            }
+        );
 
         let client: sentry::Client = self.dsn.as_str().into();
 
         let uuid = client.capture_event(
-            Event {
+            protocol::Event {
                 tags,
+                level: match event {
+                    // Late but tolerated, or about to miss, is only worth a warning.
+                    Event::Late(_) | Event::Warning(_) => Level::Warning,
+                    _ => level(s.metadata.priority),
+                },
                 logger: Some("condemn".to_owned()),
                 fingerprint: Cow::Owned(vec![Cow::Owned(fp)]),
-                message: Some(match early {
-                    Some(secs) => format!("Switch `{}` checked in early by {} seconds", name, secs),
-                    None => format!("Switch `{}` failed to make its deadline.", name),
-                }),
+                message: Some(event_message(s, event)),
                 ..Default::default()
             },
             None,
@@ -49,3 +70,12 @@ impl Notifier for SentryNotifier {
         }
     }
 }
+
+/// The Sentry level of a notification for a switch of `priority`.
+fn level(priority: Priority) -> Level {
+    match priority {
+        Priority::Low => Level::Warning,
+        Priority::Normal | Priority::High => Level::Error,
+        Priority::Critical => Level::Fatal,
+    }
+}
@@ -0,0 +1,62 @@
+use crate::notifiers::{event_message, send, Event, Notifier};
+use crate::Switch;
+
+pub enum SignalApi {
+    /// signal-cli-rest-api's `POST /v2/send`.
+    Rest,
+    /// signal-cli's own `daemon --http` JSON-RPC endpoint.
+    JsonRpc,
+}
+
+/// Sends a message from `source` to each recipient through a signal-cli server.
+pub struct SignalNotifier {
+    client: reqwest::r#async::Client,
+    base_url: String,
+    api: SignalApi,
+    source: String,
+    recipients: Vec<String>,
+}
+
+impl SignalNotifier {
+    pub fn new(base_url: &str, api: SignalApi, source: &str, recipients: Vec<String>) -> Self {
+        SignalNotifier {
+            client: reqwest::r#async::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            api,
+            source: source.to_owned(),
+            recipients,
+        }
+    }
+}
+
+impl Notifier for SignalNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let message = event_message(s, event);
+
+        let request = match self.api {
+            SignalApi::Rest => self
+                .client
+                .post(&format!("{}/v2/send", self.base_url))
+                .json(&serde_json::json!({
+                    "message": message,
+                    "number": self.source,
+                    "recipients": self.recipients,
+                })),
+            SignalApi::JsonRpc => self
+                .client
+                .post(&format!("{}/api/v1/rpc", self.base_url))
+                .json(&serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "send",
+                    "id": s.name,
+                    "params": {
+                        "account": self.source,
+                        "recipient": self.recipients,
+                        "message": message,
+                    },
+                })),
+        };
+
+        send("signal", request);
+    }
+}
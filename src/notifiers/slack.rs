@@ -0,0 +1,25 @@
+use crate::notifiers::{event_message, send, Event, Notifier};
+use crate::Switch;
+
+/// Posts a message to a Slack incoming webhook.
+pub struct SlackNotifier {
+    client: reqwest::r#async::Client,
+    webhook: String,
+}
+
+impl SlackNotifier {
+    pub fn new(webhook: &str) -> Self {
+        SlackNotifier {
+            client: reqwest::r#async::Client::new(),
+            webhook: webhook.to_owned(),
+        }
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let body = serde_json::json!({ "text": event_message(s, event) });
+
+        send("slack", self.client.post(&self.webhook).json(&body));
+    }
+}
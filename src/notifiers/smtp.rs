@@ -0,0 +1,158 @@
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use chrono::Utc;
+use futures::sync::oneshot;
+use futures::Future;
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::client::net::ClientTlsParameters;
+use lettre::smtp::{ClientSecurity, SmtpClient};
+use lettre::{EmailAddress, Envelope, SendableEmail, Transport};
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::notifiers::{event_message, spawn, Event, Notifier};
+use crate::Switch;
+
+/// How the connection to the mail server is secured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmtpSecurity {
+    /// `STARTTLS` after connecting, which the server must offer.
+    StartTls,
+    /// TLS from the start, as on port 465.
+    Tls,
+}
+
+/// The mail server and who to send as, and to. Not `Debug`, so the password isn't logged.
+#[derive(Clone)]
+pub struct SmtpServer {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Mails every event to one or more addresses. The client is blocking, so mail is sent by a
+/// thread of its own, one message at a time, over a new connection each.
+pub struct SmtpNotifier {
+    from: String,
+    to: Vec<String>,
+    mails: Mutex<Sender<(SendableEmail, oneshot::Sender<()>)>>,
+}
+
+/// Whether `address` can be mailed, for checking the URL.
+pub fn valid_address(address: &str) -> Result<(), String> {
+    EmailAddress::new(address.to_owned())
+        .map(|_| ())
+        .map_err(|e| format!("'{}'; {}", address, e))
+}
+
+impl SmtpServer {
+    fn send(&self, mail: SendableEmail) -> Result<(), String> {
+        let security = {
+            let connector = native_tls::TlsConnector::new().map_err(|e| format!("{}", e))?;
+            let tls = ClientTlsParameters::new(self.host.clone(), connector);
+            match self.security {
+                SmtpSecurity::StartTls => ClientSecurity::Required(tls),
+                SmtpSecurity::Tls => ClientSecurity::Wrapper(tls),
+            }
+        };
+
+        let mut client = SmtpClient::new((self.host.as_str(), self.port), security)
+            .map_err(|e| format!("{}", e))?;
+        if let Some((user, password)) = &self.credentials {
+            client = client.credentials(Credentials::new(user.clone(), password.clone()));
+        }
+
+        client
+            .transport()
+            .send(mail)
+            .map(|_| ())
+            .map_err(|e| format!("{}", e))
+    }
+}
+
+impl SmtpNotifier {
+    pub fn new(server: SmtpServer) -> Self {
+        let (mails, received) = channel::<(SendableEmail, oneshot::Sender<()>)>();
+        let from = server.from.clone();
+        let to = server.to.clone();
+
+        thread::spawn(move || {
+            for (mail, done) in received {
+                match server.send(mail) {
+                    Ok(()) => info!("notified smtp; host={}", server.host),
+                    Err(e) => warn!("failed to notify smtp; host={}, {}", server.host, e),
+                }
+                let _ = done.send(());
+            }
+        });
+
+        SmtpNotifier {
+            from,
+            to,
+            mails: Mutex::new(mails),
+        }
+    }
+
+    fn mail(&self, s: &Switch, event: Event) -> Result<SendableEmail, String> {
+        let address = |a: &str| EmailAddress::new(a.to_owned()).map_err(|e| format!("{}", e));
+        let envelope = Envelope::new(
+            Some(address(&self.from)?),
+            self.to
+                .iter()
+                .map(|to| address(to))
+                .collect::<Result<_, _>>()?,
+        )
+        .map_err(|e| format!("{}", e))?;
+
+        let now = Utc::now();
+        let id = format!("{}.{}@condemn", now.timestamp_nanos(), std::process::id());
+        let body = event_message(s, event)
+            .lines()
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}>\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+            self.from,
+            self.to.join(", "),
+            subject(&format!("condemn: {} {}", s.name, event.name())),
+            now.to_rfc2822(),
+            id,
+            body
+        );
+
+        Ok(SendableEmail::new(envelope, id, message.into_bytes()))
+    }
+}
+
+/// `text` as a header value, encoded if it isn't plain ASCII. Line breaks, which would start
+/// headers of their own, are left out.
+fn subject(text: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    if text.is_ascii() {
+        text
+    } else {
+        format!("=?utf-8?b?{}?=", base64::encode(text.as_bytes()))
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let mail = match self.mail(s, event) {
+            Ok(mail) => mail,
+            Err(e) => return warn!("failed to notify smtp; {}", e),
+        };
+
+        // Counted as pending until it is sent, so that shutting down waits for it.
+        let (done, sent) = oneshot::channel();
+        if self.mails.lock().send((mail, done)).is_err() {
+            return warn!("failed to notify smtp; the mailer has stopped");
+        }
+        spawn(sent.map_err(|_| ()));
+    }
+}
@@ -0,0 +1,37 @@
+use crate::notifiers::{event_message, send, Event, Notifier};
+use crate::{Priority, Switch};
+
+/// Sends a message to one or more Telegram chats through the Bot API.
+pub struct TelegramNotifier {
+    client: reqwest::r#async::Client,
+    token: String,
+    chats: Vec<String>,
+}
+
+impl TelegramNotifier {
+    pub fn new(token: &str, chats: Vec<String>) -> Self {
+        TelegramNotifier {
+            client: reqwest::r#async::Client::new(),
+            token: token.to_owned(),
+            chats,
+        }
+    }
+}
+
+impl Notifier for TelegramNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let url = format!("https://api.telegram.org/bot{}/sendMessage", self.token);
+        let text = event_message(s, event);
+
+        for chat in &self.chats {
+            let body = serde_json::json!({
+                "chat_id": chat,
+                "text": text,
+                // Low priority switches, tolerated late check-ins and warnings don't make a sound.
+                "disable_notification": s.metadata.priority == Priority::Low
+                    || matches!(event, Event::Late(_) | Event::Warning(_)),
+            });
+            send("telegram", self.client.post(&url).json(&body));
+        }
+    }
+}
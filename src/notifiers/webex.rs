@@ -0,0 +1,38 @@
+use crate::notifiers::{event_message, send, Event, Notifier};
+use crate::Switch;
+
+const WEBEX_MESSAGES_URL: &str = "https://webexapis.com/v1/messages";
+
+/// Posts a markdown message to a Webex room as a bot.
+pub struct WebexNotifier {
+    client: reqwest::r#async::Client,
+    token: String,
+    room: String,
+}
+
+impl WebexNotifier {
+    pub fn new(token: &str, room: &str) -> Self {
+        WebexNotifier {
+            client: reqwest::r#async::Client::new(),
+            token: token.to_owned(),
+            room: room.to_owned(),
+        }
+    }
+}
+
+impl Notifier for WebexNotifier {
+    fn notify(&self, s: &Switch, event: Event) {
+        let body = serde_json::json!({
+            "roomId": self.room,
+            "markdown": event_message(s, event),
+        });
+
+        send(
+            "webex",
+            self.client
+                .post(WEBEX_MESSAGES_URL)
+                .bearer_auth(&self.token)
+                .json(&body),
+        );
+    }
+}
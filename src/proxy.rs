@@ -0,0 +1,415 @@
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{loop_fn, ok, Either, Loop};
+use futures::sync::mpsc;
+use futures::{task_local, Future, Poll, Stream};
+use log::{info, warn};
+use tokio::io::{read_exact, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Timeout};
+use warp::http::HeaderMap;
+use warp::{Filter, Rejection};
+
+use crate::access;
+
+/// How long a proxy has to send the PROXY protocol header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest version 1 header, from the PROXY protocol spec.
+const MAX_V1_LENGTH: usize = 107;
+
+/// The start of a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// The address a connection read on this task is from, for when warp doesn't know it.
+task_local! {
+    static PEER: Cell<Option<SocketAddr>> = Cell::new(None)
+}
+
+/// A network as `address/prefix`, such as `10.0.0.0/8`, or a single address.
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+/// The address of the client, as `resolve` found it.
+#[derive(Debug, Clone, Copy)]
+pub struct Client(pub Option<IpAddr>);
+
+/// The reverse proxies condemn is behind. Their `X-Forwarded-For` is believed.
+pub struct Proxies {
+    trusted: Vec<Network>,
+}
+
+/// The address as bits, with IPv4 mapped IPv6 addresses as IPv4, and how many there are.
+fn bits(ip: IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => (u128::from(ip) & 0xffff_ffff, 32),
+            _ => (u128::from(ip), 128),
+        },
+    }
+}
+
+/// Parses a network as `address/prefix`, or an address alone for just that one.
+pub fn parse_network(v: &str) -> Result<Network, String> {
+    let (addr, prefix) = match v.find('/') {
+        Some(i) => (&v[..i], Some(&v[i + 1..])),
+        None => (v, None),
+    };
+
+    let addr: IpAddr = addr.parse().map_err(|e| format!("{}", e))?;
+    let (_, max) = bits(addr);
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().map_err(|e| format!("{}", e))?,
+        None => max,
+    };
+    if prefix > max {
+        return Err(format!("the prefix should be at most {}", max));
+    }
+
+    Ok(Network { addr, prefix })
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, len) = bits(self.addr);
+        let (ip, ip_len) = bits(ip);
+        let shift = len - self.prefix;
+
+        len == ip_len && (self.prefix == 0 || network >> shift == ip >> shift)
+    }
+}
+
+impl Proxies {
+    pub fn new(trusted: Vec<Network>) -> Self {
+        Self { trusted }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|network| network.contains(ip))
+    }
+
+    /// The client of a request from `peer`. If `peer` is a proxy it is the last address in
+    /// `X-Forwarded-For` that isn't one too. Those before it are whatever the client sent so are
+    /// not believed.
+    fn client(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = peer?;
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        let forwarded: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect();
+
+        for addr in forwarded.into_iter().rev() {
+            let ip = match addr.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => match addr.parse::<SocketAddr>() {
+                    Ok(addr) => addr.ip(),
+                    Err(_) => break,
+                },
+            };
+
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+
+        Some(client)
+    }
+}
+
+/// Finds the client of the request for `client()` and the request log. It goes before all of the
+/// routes.
+pub fn resolve(proxies: Arc<Proxies>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .and_then(move |remote: Option<SocketAddr>, headers: HeaderMap| {
+            let peer = remote.or_else(|| PEER.with(Cell::get));
+            let client = proxies.client(peer.map(|peer| peer.ip()), &headers);
+
+            access::start(client);
+            warp::ext::set(Client(client));
+            Ok::<_, Rejection>(())
+        })
+        .untuple_one()
+}
+
+/// The address of the client `resolve` found for the request.
+pub fn client() -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Copy {
+    warp::ext::get::<Client>().map(|client: Client| client.0)
+}
+
+/// A connection that tells the requests read from it who it is from, which warp can't when it
+/// is given connections rather than listening itself.
+#[derive(Debug)]
+pub struct Connection<S> {
+    stream: S,
+    peer: Option<SocketAddr>,
+}
+
+impl Connection<TcpStream> {
+    /// A connection from whoever is at the other end.
+    pub fn direct(tcp: TcpStream) -> Self {
+        Self {
+            peer: tcp.peer_addr().ok(),
+            stream: tcp,
+        }
+    }
+}
+
+impl<S: Read> Read for Connection<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let peer = self.peer;
+        PEER.with(|p| p.set(peer));
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Connection<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Connection<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Connection<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// The source address of a version 1 header, such as `PROXY TCP4 192.0.2.1 192.0.2.2 1234 80`, or
+/// `None` for `PROXY UNKNOWN`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("header isn't text"))?;
+    let line = line.trim_end_matches("\r\n").trim_start_matches("PROXY ");
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4", src, _, port, _] | ["TCP6", src, _, port, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("unsupported version 1 header")),
+    }
+}
+
+/// The source address of a version 2 header, given the 4 bytes after the signature and the
+/// addresses, or `None` for a `LOCAL` connection or a family other than TCP.
+fn parse_v2(head: &[u8], addrs: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    if head[0] >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    if head[0] & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    let port = |at: usize| u16::from(addrs[at]) << 8 | u16::from(addrs[at + 1]);
+    match head[1] {
+        0x11 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        0x21 if addrs.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                port(32),
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reads the PROXY protocol header, version 1 or 2, from the start of `tcp`. The connection is
+/// from the address in it, or from the proxy itself for those such as its health checks.
+pub fn accept(tcp: TcpStream) -> impl Future<Item = Connection<TcpStream>, Error = io::Error> {
+    let proxy = tcp.peer_addr().ok();
+
+    let header = read_exact(tcp, [0; 5]).and_then(|(tcp, start)| {
+        if &start == b"PROXY" {
+            let v1 = loop_fn((tcp, start.to_vec()), |(tcp, mut line)| {
+                read_exact(tcp, [0; 1]).and_then(|(tcp, byte)| {
+                    line.push(byte[0]);
+                    if byte[0] == b'\n' {
+                        Ok(Loop::Break((tcp, parse_v1(&line)?)))
+                    } else if line.len() >= MAX_V1_LENGTH {
+                        Err(invalid("version 1 header is too long"))
+                    } else {
+                        Ok(Loop::Continue((tcp, line)))
+                    }
+                })
+            });
+            Either::A(Either::A(v1))
+        } else if start == V2_SIGNATURE[..5] {
+            let v2 = read_exact(tcp, [0; 11])
+                .and_then(|(tcp, rest)| {
+                    if rest[..7] != V2_SIGNATURE[5..] {
+                        return Err(invalid("bad version 2 signature"));
+                    }
+                    let len = usize::from(rest[9]) << 8 | usize::from(rest[10]);
+                    Ok((tcp, [rest[7], rest[8], rest[9], rest[10]], len))
+                })
+                .and_then(|(tcp, head, len)| {
+                    read_exact(tcp, vec![0; len])
+                        .and_then(move |(tcp, addrs)| Ok((tcp, parse_v2(&head, &addrs)?)))
+                });
+            Either::A(Either::B(v2))
+        } else {
+            Either::B(futures::future::err(invalid("no PROXY protocol header")))
+        }
+    });
+
+    Timeout::new(header, HEADER_TIMEOUT)
+        .map_err(|e| {
+            e.into_inner().unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::TimedOut, "timed out reading header")
+            })
+        })
+        .map(move |(tcp, peer)| Connection {
+            stream: tcp,
+            peer: peer.or(proxy),
+        })
+}
+
+/// Listens on `addr` for HTTP behind a proxy using the PROXY protocol. The returned future accepts
+/// connections and must be spawned, the stream yields them once their headers have been read for
+/// `warp::serve().serve_incoming()`.
+pub fn listen(
+    addr: &SocketAddr,
+) -> Result<
+    (
+        impl Future<Item = (), Error = ()>,
+        impl Stream<Item = Connection<TcpStream>, Error = io::Error>,
+    ),
+    String,
+> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("{}; {}", addr, e))?;
+    let (tx, rx) = mpsc::unbounded();
+
+    // Headers are read on their own so that a slow proxy doesn't hold up the others.
+    let accept = listener.incoming().then(Ok).for_each(move |tcp| match tcp {
+        Ok(tcp) => {
+            let tx = tx.clone();
+            let header = self::accept(tcp)
+                .map_err(|e| info!("PROXY protocol header refused; {}", e))
+                .map(move |connection| {
+                    let _ = tx.unbounded_send(connection);
+                });
+            tokio::spawn(header);
+
+            Either::A(ok(()))
+        }
+        // Such as running out of file descriptors. Give it a moment rather than spin.
+        Err(e) => {
+            warn!("accept failure; {}", e);
+            Either::B(Delay::new(Instant::now() + Duration::from_millis(100)).map_err(|_| ()))
+        }
+    });
+
+    let incoming = rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "accept loop ended"));
+
+    Ok((accept, incoming))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, ip: &str) -> bool {
+        parse_network(network)
+            .unwrap()
+            .contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_networks() {
+        let network = parse_network("10.0.0.0/8").unwrap();
+        assert_eq!(network.addr, "10.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(network.prefix, 8);
+
+        assert_eq!(parse_network("10.1.2.3").unwrap().prefix, 32);
+        assert_eq!(parse_network("fd00::/8").unwrap().prefix, 8);
+        assert_eq!(parse_network("::1").unwrap().prefix, 128);
+        assert_eq!(parse_network("0.0.0.0/0").unwrap().prefix, 0);
+        // IPv4 mapped addresses are taken as IPv4.
+        assert_eq!(parse_network("::ffff:10.1.2.3").unwrap().prefix, 32);
+    }
+
+    #[test]
+    fn refuses_invalid_networks() {
+        for v in &[
+            "",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "fd00::/129",
+            "::ffff:10.0.0.0/104",
+            "proxy.example.net",
+        ] {
+            assert!(parse_network(v).is_err(), "{}", v);
+        }
+    }
+
+    #[test]
+    fn contains_addresses_under_the_prefix() {
+        assert!(contains("10.0.0.0/8", "10.255.1.2"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.0/25", "192.168.1.127"));
+        assert!(!contains("192.168.1.0/25", "192.168.1.128"));
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+    }
+
+    #[test]
+    fn contains_only_the_address_itself() {
+        assert!(contains("10.1.2.3", "10.1.2.3"));
+        assert!(!contains("10.1.2.3", "10.1.2.4"));
+        assert!(contains("::1", "::1"));
+        assert!(!contains("::1", "::2"));
+    }
+
+    #[test]
+    fn contains_everything_of_its_family_at_prefix_0() {
+        assert!(contains("0.0.0.0/0", "0.0.0.0"));
+        assert!(contains("0.0.0.0/0", "255.255.255.255"));
+        assert!(contains("::/0", "::"));
+        assert!(contains("::/0", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("::/0", "10.0.0.1"));
+    }
+
+    #[test]
+    fn contains_ipv4_mapped_addresses_as_ipv4() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(contains("::ffff:10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("::/0", "::ffff:10.1.2.3"));
+    }
+}
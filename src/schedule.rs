@@ -0,0 +1,427 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// How far ahead to look for the next run. Long enough to find February 29th after a century year
+/// that isn't a leap year.
+const LOOKAHEAD_DAYS: i64 = 8 * 366;
+
+/// A cron expression of minute, hour, day of month, month and day of week, in UTC unless it is
+/// prefixed with `CRON_TZ=` and an IANA timezone. Each field is `*`, a number or a range `a-b`,
+/// optionally stepped with `/n`, or a comma separated list of them. Months and days of the week
+/// can also be their first three letters and Sunday is 0 or 7. As with cron, if both day fields
+/// are restricted a day matching either will do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    expression: String,
+    timezone: Option<Tz>,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn has(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+fn parse_value(s: &str, names: &[&str], first: u32) -> Result<u32, String> {
+    if let Some(i) = names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+        return Ok(i as u32 + first);
+    }
+
+    s.parse().map_err(|_| format!("'{}' isn't a number", s))
+}
+
+/// The set of values `field` allows between `min` and `max`, as bits.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut set = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], Some(&part[i + 1..])),
+            None => (part, None),
+        };
+
+        let step = match step {
+            None => 1,
+            Some(step) => match step.parse() {
+                Ok(step) if step > 0 => step,
+                _ => return Err(format!("'{}' has an invalid step", part)),
+            },
+        };
+
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (
+                parse_value(&range[..i], names, min)?,
+                parse_value(&range[i + 1..], names, min)?,
+            )
+        } else {
+            let value = parse_value(range, names, min)?;
+            // `5/15` is every 15 from 5 on.
+            (value, if step > 1 { max } else { value })
+        };
+
+        if lo < min || hi > max || lo > hi {
+            return Err(format!("'{}' is outside {}-{}", part, min, max));
+        }
+
+        for n in (lo..=hi).step_by(step) {
+            set |= 1 << n;
+        }
+    }
+
+    Ok(set)
+}
+
+/// An IANA timezone such as `Europe/Berlin`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timezone(Tz);
+
+impl<'de> Deserialize<'de> for Timezone {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse()
+            .map(Timezone)
+            .map_err(|_| de::Error::custom(format!("'{}' isn't a known timezone", name)))
+    }
+}
+
+impl Schedule {
+    /// The schedule in `timezone` rather than the one it has.
+    pub fn in_timezone(self, timezone: Timezone) -> Schedule {
+        Schedule {
+            timezone: Some(timezone.0),
+            ..self
+        }
+    }
+
+    /// Whether the schedule runs in the minute of `t`.
+    pub fn contains(&self, t: DateTime<Utc>) -> bool {
+        let local = t
+            .with_timezone(&self.timezone.unwrap_or(Tz::UTC))
+            .naive_local();
+
+        has(self.months, local.month())
+            && self.day_matches(&local)
+            && has(self.hours, local.hour())
+            && has(self.minutes, local.minute())
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let day = has(self.days, t.day());
+        let weekday = has(self.weekdays, t.weekday().num_days_from_sunday());
+
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+
+    /// The first time after `t` that the schedule runs, to the minute. None if it doesn't within
+    /// the next several years, which is only so of schedules that never run.
+    ///
+    /// Runs are worked out on the clock of the schedule's timezone. A run in the hour skipped when
+    /// the clocks go forward doesn't happen, and one in the hour repeated when they go back
+    /// happens the first time round.
+    pub fn next_after(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let timezone = self.timezone.unwrap_or(Tz::UTC);
+        let mut local = t.with_timezone(&timezone).naive_local();
+        local = local.with_nanosecond(0)?.with_second(0)? + Duration::minutes(1);
+        let end = local + Duration::days(LOOKAHEAD_DAYS);
+
+        while local < end {
+            if !has(self.months, local.month()) {
+                let (year, month) = match local.month() {
+                    12 => (local.year() + 1, 1),
+                    month => (local.year(), month + 1),
+                };
+                local = NaiveDate::from_ymd(year, month, 1).and_hms(0, 0, 0);
+            } else if !self.day_matches(&local) {
+                local = local.date().and_hms(0, 0, 0) + Duration::days(1);
+            } else if !has(self.hours, local.hour()) {
+                local = local.date().and_hms(local.hour(), 0, 0) + Duration::hours(1);
+            } else if !has(self.minutes, local.minute()) {
+                local += Duration::minutes(1);
+            } else {
+                let run = timezone.from_local_datetime(&local).earliest();
+                match run.map(|run| run.with_timezone(&Utc)) {
+                    Some(run) if run > t => return Some(run),
+                    _ => local += Duration::minutes(1),
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, String> {
+        let mut fields: Vec<&str> = expression.split_whitespace().collect();
+
+        let timezone = match fields.first().and_then(|f| f.strip_prefix("CRON_TZ=")) {
+            Some(name) => {
+                let timezone = name
+                    .parse()
+                    .map_err(|_| format!("'{}' isn't a known timezone", name))?;
+                fields.remove(0);
+                Some(timezone)
+            }
+            None => None,
+        };
+
+        if fields.len() != 5 {
+            return Err(format!(
+                "'{}' should have 5 fields; minute hour day month weekday",
+                expression
+            ));
+        }
+
+        let weekdays = parse_field(fields[4], 0, 7, WEEKDAYS)?;
+
+        let schedule = Schedule {
+            expression: fields.join(" "),
+            timezone,
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, MONTHS)?,
+            // Sunday is both 0 and 7.
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        };
+
+        match schedule.next_after(Utc::now()) {
+            Some(_) => Ok(schedule),
+            None => Err(format!("'{}' never runs", expression)),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.timezone {
+            Some(timezone) => write!(f, "CRON_TZ={} {}", timezone.name(), self.expression),
+            None => f.write_str(&self.expression),
+        }
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expression = String::deserialize(deserializer)?;
+        expression.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(t: &str) -> DateTime<Utc> {
+        t.parse().unwrap()
+    }
+
+    fn next(expression: &str, t: &str) -> DateTime<Utc> {
+        let schedule: Schedule = expression.parse().unwrap();
+        schedule.next_after(at(t)).unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let schedule: Schedule = "*/15 9-17 * jan-mar mon,fri".parse().unwrap();
+
+        assert_eq!(schedule.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(schedule.hours, (9..=17).fold(0u64, |set, n| set | 1 << n));
+        assert_eq!(schedule.months, 1 << 1 | 1 << 2 | 1 << 3);
+        assert_eq!(schedule.weekdays, 1 << 1 | 1 << 5);
+        assert!(schedule.any_day);
+        assert!(!schedule.any_weekday);
+    }
+
+    #[test]
+    fn steps_from_a_value() {
+        let schedule: Schedule = "5/20 * * * *".parse().unwrap();
+        assert_eq!(schedule.minutes, 1 << 5 | 1 << 25 | 1 << 45);
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        let zero: Schedule = "0 0 * * 0".parse().unwrap();
+        let seven: Schedule = "0 0 * * 7".parse().unwrap();
+        let sun: Schedule = "0 0 * * SUN".parse().unwrap();
+
+        assert_eq!(zero.weekdays, 1);
+        assert_eq!(seven.weekdays, 1);
+        assert_eq!(sun.weekdays, 1);
+    }
+
+    #[test]
+    fn refuses_invalid() {
+        for expression in &[
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "x * * * *",
+            "CRON_TZ=Nowhere/Special * * * * *",
+            "0 0 30 feb *",
+        ] {
+            assert!(expression.parse::<Schedule>().is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn displays_as_parsed() {
+        for expression in &["*/5 * * * *", "CRON_TZ=Europe/Berlin 0 3 * * *"] {
+            let schedule: Schedule = expression.parse().unwrap();
+            assert_eq!(schedule.to_string(), *expression);
+        }
+    }
+
+    #[test]
+    fn next_is_strictly_after() {
+        assert_eq!(
+            next("*/15 * * * *", "2026-10-14T10:07:30Z"),
+            at("2026-10-14T10:15:00Z")
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2026-10-14T10:15:00Z"),
+            at("2026-10-14T10:30:00Z")
+        );
+    }
+
+    #[test]
+    fn next_rolls_over_the_year() {
+        assert_eq!(
+            next("0 0 1 jan *", "2026-10-14T00:00:00Z"),
+            at("2027-01-01T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn next_finds_leap_days() {
+        assert_eq!(
+            next("0 12 29 feb *", "2026-10-14T00:00:00Z"),
+            at("2028-02-29T12:00:00Z")
+        );
+    }
+
+    #[test]
+    fn either_day_field_will_do_when_both_are_restricted() {
+        // The 13th or any Friday. 2026-10-14 is a Wednesday, the 16th a Friday and December 13th
+        // a Sunday.
+        assert_eq!(
+            next("0 0 13 * 5", "2026-10-14T00:00:00Z"),
+            at("2026-10-16T00:00:00Z")
+        );
+        assert_eq!(
+            next("0 0 13 * 5", "2026-12-11T00:00:00Z"),
+            at("2026-12-13T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn both_day_fields_must_match_when_one_is_any() {
+        assert_eq!(
+            next("0 0 13 * *", "2026-10-14T00:00:00Z"),
+            at("2026-11-13T00:00:00Z")
+        );
+        assert_eq!(
+            next("0 0 * * fri", "2026-10-14T00:00:00Z"),
+            at("2026-10-16T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn runs_on_the_clock_of_its_timezone() {
+        // Berlin is 2 hours ahead in the summer and 1 in the winter.
+        assert_eq!(
+            next("CRON_TZ=Europe/Berlin 0 3 * * *", "2026-07-01T00:00:00Z"),
+            at("2026-07-01T01:00:00Z")
+        );
+        assert_eq!(
+            next("CRON_TZ=Europe/Berlin 0 3 * * *", "2026-12-01T00:00:00Z"),
+            at("2026-12-01T02:00:00Z")
+        );
+    }
+
+    #[test]
+    fn in_timezone_replaces_the_timezone() {
+        let schedule: Schedule = "0 3 * * *".parse().unwrap();
+        let berlin: Timezone = serde_json::from_str("\"Europe/Berlin\"").unwrap();
+        let schedule = schedule.in_timezone(berlin);
+
+        assert_eq!(schedule.to_string(), "CRON_TZ=Europe/Berlin 0 3 * * *");
+        assert_eq!(
+            schedule.next_after(at("2026-07-01T00:00:00Z")),
+            Some(at("2026-07-01T01:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn skips_runs_in_the_hour_the_clocks_go_forward() {
+        // In Berlin 02:00 became 03:00 on 2026-03-29.
+        assert_eq!(
+            next("CRON_TZ=Europe/Berlin 30 2 * * *", "2026-03-28T12:00:00Z"),
+            at("2026-03-30T00:30:00Z")
+        );
+        assert_eq!(
+            next("CRON_TZ=Europe/Berlin 0 3 * * *", "2026-03-28T12:00:00Z"),
+            at("2026-03-29T01:00:00Z")
+        );
+    }
+
+    #[test]
+    fn runs_once_in_the_hour_the_clocks_go_back() {
+        // In Berlin 03:00 became 02:00 on 2026-10-25, so 02:30 came round twice.
+        assert_eq!(
+            next("CRON_TZ=Europe/Berlin 30 2 * * *", "2026-10-24T12:00:00Z"),
+            at("2026-10-25T00:30:00Z")
+        );
+        assert_eq!(
+            next("CRON_TZ=Europe/Berlin 30 2 * * *", "2026-10-25T00:30:00Z"),
+            at("2026-10-26T01:30:00Z")
+        );
+    }
+
+    #[test]
+    fn contains_the_minutes_it_runs_in() {
+        let schedule: Schedule = "CRON_TZ=Europe/Berlin 0-29 9 * * mon-fri".parse().unwrap();
+
+        assert!(schedule.contains(at("2026-10-14T07:15:59Z")));
+        assert!(!schedule.contains(at("2026-10-14T07:30:00Z")));
+        assert!(!schedule.contains(at("2026-10-14T09:15:00Z")));
+        assert!(!schedule.contains(at("2026-10-18T07:15:00Z")));
+    }
+}
@@ -1,67 +1,313 @@
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
-use futures::future::{err, ok, Either};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use fs2::FileExt;
+use futures::future::{err, Either};
 use futures::stream::Stream;
-use futures::Future;
+use futures::{future, Future};
 use log::{info, warn};
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::stores::Store;
+use crate::stores::encoding::{self, BinarySwitch, Format};
+use crate::stores::encryption::{self, Key};
+use crate::stores::{Store, Updater};
 use crate::Switch;
 
-#[derive(Debug)]
+/// Once there are this many records since the last compaction the journal is compacted, as long
+/// as that is also more than the number of records the compaction left.
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+/// DiskStore serves reads from the wrapped store and keeps the file as an append-only journal of
+/// inserts and takes, one record per line, so each change costs a single small write. Every so
+/// often the journal is compacted down to one insert per live switch.
+///
+/// Records are JSON or, in the binary format, base64 of the binary layout. A journal may mix both.
+///
+/// Files written by older versions, a JSON array of switches, are converted on start up.
+///
+/// Only one process may use a file at a time; `init()` fails if another holds its lock.
+///
+/// With a key each record is encrypted before it is written.
+#[derive(Debug, Clone)]
 pub struct DiskStore<S: Store> {
     filename: PathBuf,
+    compression: Compression,
+    key: Option<Key>,
+    format: Format,
     store: S,
+    journal: Arc<Mutex<Journal>>,
+}
+
+/// How the file is written. Each append is compressed separately, as its own gzip member or zstd
+/// frame, which both formats allow to be concatenated. Loading detects the format regardless of
+/// this setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::stream::encode_all(data, 0),
+        }
+    }
+}
+
+/// The contents of a file in any of the `Compression` formats. Anything decoded before an error,
+/// such as a crash cutting the last append short, is returned along with the error.
+fn decompress(data: &[u8]) -> (Vec<u8>, Option<std::io::Error>) {
+    let mut decoder: Box<Read> = if data.starts_with(&[0x1f, 0x8b]) {
+        Box::new(MultiGzDecoder::new(data))
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        match zstd::stream::Decoder::new(data) {
+            Ok(decoder) => Box::new(decoder),
+            Err(e) => return (vec![], Some(e)),
+        }
+    } else {
+        return (data.to_vec(), None);
+    };
+
+    let mut out = vec![];
+    let res = decoder.read_to_end(&mut out);
+    (out, res.err())
+}
+
+#[derive(Debug, Default)]
+struct Journal {
+    /// `<filename>.lock`, exclusively locked from `init()` for as long as the process runs so that
+    /// another process can't use the same file. A separate file since compaction replaces the
+    /// journal.
+    lock: Option<File>,
+    /// Opened for appending by `init()`.
+    file: Option<File>,
+    records: usize,
+    /// The number of records left by the last compaction.
+    compacted: usize,
+    /// Records appended while a compaction is taking its snapshot. They are replayed on to the
+    /// compacted journal since the snapshot may or may not include them.
+    pending: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Insert { switch: Box<Switch> },
+    Take { name: String },
+}
+
+/// A `Record` in the binary format.
+#[derive(Serialize, Deserialize)]
+enum BinaryRecord {
+    Insert(BinarySwitch),
+    Take(String),
+}
+
+impl From<&Record> for BinaryRecord {
+    fn from(record: &Record) -> Self {
+        match record {
+            Record::Insert { switch } => BinaryRecord::Insert(switch.as_ref().into()),
+            Record::Take { name } => BinaryRecord::Take(name.clone()),
+        }
+    }
+}
+
+impl From<BinaryRecord> for Record {
+    fn from(record: BinaryRecord) -> Self {
+        match record {
+            BinaryRecord::Insert(switch) => Record::Insert {
+                switch: Box::new(switch.into()),
+            },
+            BinaryRecord::Take(name) => Record::Take { name },
+        }
+    }
 }
 
 impl<S: 'static + Clone + Store + Send + Sync> DiskStore<S> {
-    pub fn new<P: AsRef<Path>>(store: S, filename: P) -> Self {
+    pub fn new<P: AsRef<Path>>(
+        store: S,
+        filename: P,
+        compression: Compression,
+        key: Option<Key>,
+        format: Format,
+    ) -> Self {
         Self {
             filename: filename.as_ref().to_path_buf(),
+            compression,
+            key,
+            format,
             store: store,
+            journal: Arc::new(Mutex::new(Journal::default())),
         }
     }
+
+    fn append(&self, records: &[Record]) -> Result<(), ()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let lines = records
+            .iter()
+            .map(|r| serialize_record(r, self.format, self.key.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut journal = self.journal.lock();
+
+        if let Some(pending) = journal.pending.as_mut() {
+            pending.extend(lines.iter().cloned());
+        }
+
+        let compression = self.compression;
+        let res = match journal.file.as_mut() {
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "journal is not open",
+            )),
+            Some(fh) => compression
+                .compress(lines.concat().as_bytes())
+                .and_then(|data| fh.write_all(&data))
+                .and_then(|_| fh.sync_data()),
+        };
+
+        journal.records += lines.len();
+
+        res.map_err(|e| warn!("failed to write db file '{:?}'; {}", self.filename, e))
+    }
+
+    fn lock(&self) -> Result<(), ()> {
+        let mut lock_name = self.filename.as_os_str().to_owned();
+        lock_name.push(".lock");
+        let lock_name = PathBuf::from(lock_name);
+
+        let fh = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&lock_name)
+            .map_err(|e| warn!("failed to open lock file '{:?}'; {}", lock_name, e))?;
+
+        fh.try_lock_exclusive().map_err(|e| {
+            warn!(
+                "db file '{:?}' is in use by another process; lock={:?}, {}",
+                self.filename, lock_name, e
+            )
+        })?;
+
+        self.journal.lock().lock = Some(fh);
+
+        Ok(())
+    }
+
+    fn should_compact(&self) -> bool {
+        let journal = self.journal.lock();
+        let since = journal.records - journal.compacted;
+        since >= COMPACT_MIN_RECORDS && since > journal.compacted
+    }
+
+    /// Rewrites the journal as one insert per switch in the wrapped store.
+    fn compact(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        self.journal.lock().pending = Some(vec![]);
+
+        let journal = Arc::clone(&self.journal);
+        let filename = self.filename.clone();
+        let compression = self.compression;
+        let key = self.key.clone();
+        let format = self.format;
+
+        Box::new(self.store.all().then(move |res| {
+            let mut journal = journal.lock();
+            let pending = journal.pending.take().unwrap_or_default();
+
+            let mut lines = res?
+                .into_iter()
+                .map(|switch| Record::Insert {
+                    switch: Box::new(switch),
+                })
+                .map(|record| serialize_record(&record, format, key.as_ref()))
+                .collect::<Result<Vec<_>, _>>()?;
+            lines.extend(pending);
+
+            let fh = compression
+                .compress(lines.concat().as_bytes())
+                .and_then(|data| write_file(&filename, &data))
+                .and_then(|_| OpenOptions::new().append(true).open(&filename))
+                .map_err(|e| warn!("failed to compact db file '{:?}'; {}", filename, e))?;
+
+            info!(
+                "compacted db file '{:?}'; records={}, remaining={}",
+                filename,
+                journal.records,
+                lines.len()
+            );
+
+            journal.file = Some(fh);
+            journal.records = lines.len();
+            journal.compacted = lines.len();
+
+            Ok(())
+        }))
+    }
 }
 
 impl<S: 'static + Clone + Store + Send + Sync> Store for DiskStore<S> {
     fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
         info!("Loading data from '{:?}'", self.filename);
 
-        let r = self.store.clone();
-        let filename = self.filename.clone();
-
-        let result: Result<Vec<Switch>, _> = OpenOptions::new()
-            .read(true)
-            .open(&self.filename)
-            .and_then(|fh| {
-                Ok(serde_json::from_reader(fh).unwrap_or_else(|e| {
-                    warn!("failed to deserialize db file '{:?}'; {}", self.filename, e);
-                    vec![]
-                }))
-            });
+        if self.lock().is_err() {
+            return Box::new(err(()));
+        }
 
-        let f = match result {
-            Err(e) => Either::A({
+        let data = match fs::read(&self.filename) {
+            Ok(data) => data,
+            Err(e) => {
                 warn!("failed to open db file '{:?}'; {}", self.filename, e);
-                err(())
-            }),
-            Ok(data) => Either::B(
-                futures::stream::futures_unordered(
-                    data.into_iter().map(|sw: Switch| self.store.insert(sw)),
-                )
-                .collect()
-                .and_then(move |_| {
-                    r.all().and_then(|data: Vec<Switch>| {
-                        write_switches(filename, &data).unwrap();
-                        ok(())
-                    })
-                }),
-            ),
+                return Box::new(err(()));
+            }
         };
 
+        let (data, error) = decompress(&data);
+        if let Some(e) = error {
+            warn!("failed to decompress db file '{:?}'; {}", self.filename, e);
+        }
+        let data = String::from_utf8_lossy(&data);
+
+        let switches = if data.trim_start().starts_with('[') {
+            serde_json::from_str(&data).unwrap_or_else(|e| {
+                warn!("failed to deserialize db file '{:?}'; {}", self.filename, e);
+                vec![]
+            })
+        } else {
+            match replay(&self.filename, &data, self.key.as_ref()) {
+                Ok(switches) => switches,
+                Err(e) => {
+                    warn!("failed to load db file '{:?}'; {}", self.filename, e);
+                    return Box::new(err(()));
+                }
+            }
+        };
+
+        let this = self.clone();
+
+        let f = futures::stream::futures_unordered(
+            switches.into_iter().map(|sw: Switch| self.store.insert(sw)),
+        )
+        .collect()
+        .and_then(move |_| this.compact());
+
         Box::new(f)
     }
 
@@ -69,65 +315,208 @@ impl<S: 'static + Clone + Store + Send + Sync> Store for DiskStore<S> {
         self.store.all()
     }
 
-    fn expired(&self, when: DateTime<Utc>) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
-        let filename = self.filename.clone();
-        let w = self.store.clone();
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        self.store.get(name)
+    }
+
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        let this = self.clone();
+        let compact = self.clone();
+
+        let batches = self
+            .store
+            .expired(when, batch)
+            .chunks(batch)
+            .and_then(move |switches| {
+                let takes: Vec<_> = switches
+                    .iter()
+                    .map(|s| Record::Take {
+                        name: s.name.clone(),
+                    })
+                    .collect();
+
+                this.append(&takes).map(|_| switches)
+            });
+
+        // Once the last batch has been written.
+        let compacted = futures::stream::once(Ok(()))
+            .and_then(move |_| {
+                if compact.should_compact() {
+                    Either::A(compact.compact())
+                } else {
+                    Either::B(future::ok(()))
+                }
+            })
+            .filter_map(|_| None);
 
-        // Sync before pop'ing off the expired ones. Better safe than sorry.
         Box::new(
-            self.store
-                .all()
-                .and_then(move |data: Vec<Switch>| {
-                    write_switches(filename, &data).unwrap();
-                    ok(())
-                })
-                .and_then(move |_| w.expired(when)),
+            batches
+                .chain(compacted)
+                .map(futures::stream::iter_ok)
+                .flatten(),
         )
     }
 
     fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
-        let filename = self.filename.clone();
-        let r = self.store.clone();
-
-        let f = self.store.insert(s).and_then(move |_| {
-            r.all().and_then(|data: Vec<Switch>| {
-                write_switches(filename, &data).unwrap();
-                ok(())
-            })
-        });
+        let record = Record::Insert {
+            switch: Box::new(s.clone()),
+        };
+        let this = self.clone();
 
-        Box::new(f)
+        Box::new(
+            self.store
+                .insert(s)
+                .and_then(move |_| this.append(&[record])),
+        )
     }
 
     fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
-        let filename = self.filename.clone();
-        let r = self.store.clone();
+        let record = Record::Take {
+            name: name.to_owned(),
+        };
 
-        // Sync _after_ the take() here. Why? Because we expect it to be gone.
-        let f = self.store.take(name).and_then(move |s| {
-            r.all().and_then(move |data: Vec<Switch>| {
-                write_switches(filename, &data).unwrap();
-                ok(s)
-            })
-        });
+        let this = self.clone();
 
-        Box::new(f)
+        Box::new(
+            self.store
+                .take(name)
+                .and_then(move |s| this.append(&[record]).map(move |_| s)),
+        )
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let name = name.to_owned();
+        let this = self.clone();
+
+        Box::new(self.store.update(&name, f).and_then(move |(old, new)| {
+            let records = match (&old, &new) {
+                (_, Some(s)) => vec![Record::Insert {
+                    switch: Box::new(s.clone()),
+                }],
+                (Some(_), None) => vec![Record::Take { name }],
+                (None, None) => vec![],
+            };
+
+            this.append(&records).map(move |_| (old, new))
+        }))
+    }
+
+    /// Writes and removes a file next to the journal, which fails if the disk is full or gone
+    /// read-only.
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let mut probe = self.filename.as_os_str().to_owned();
+        probe.push(".probe");
+        let probe = PathBuf::from(probe);
+
+        let res = write_file(&probe, b"ok")
+            .and_then(|_| fs::remove_file(&probe))
+            .map_err(|e| warn!("failed to write probe file '{:?}'; {}", probe, e));
+
+        Box::new(future::result(res))
+    }
+
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        self.store.next_due()
+    }
+}
+
+/// The switches left after applying every record in `data`. A bad record, most likely the last
+/// one being cut short by a crash, is logged and skipped.
+///
+/// A record that can't be decrypted fails the whole replay, unless it is the last of several,
+/// since carrying on without the right key would lose every switch at the next compaction.
+fn replay(filename: &Path, data: &str, key: Option<&Key>) -> Result<Vec<Switch>, String> {
+    let mut switches = HashMap::new();
+
+    let lines: Vec<_> = data.lines().filter(|l| !l.trim().is_empty()).collect();
+
+    for (i, line) in lines.iter().enumerate() {
+        let line = match encryption::decode(key, line) {
+            Ok(line) => line,
+            Err(e) if key.is_none() || i == 0 || i + 1 < lines.len() => {
+                return Err(format!("record {}; {}", i + 1, e))
+            }
+            Err(e) => {
+                warn!(
+                    "skipping bad record in db file '{:?}'; record={}, {}",
+                    filename,
+                    i + 1,
+                    e
+                );
+                continue;
+            }
+        };
+
+        match deserialize_record(&line) {
+            Ok(Record::Insert { switch }) => {
+                switches.insert(switch.name.clone(), *switch);
+            }
+            Ok(Record::Take { name }) => {
+                switches.remove(&name);
+            }
+            Err(e) => warn!(
+                "skipping bad record in db file '{:?}'; record={}, {}",
+                filename,
+                i + 1,
+                e
+            ),
+        }
     }
+
+    Ok(switches.into_iter().map(|(_, s)| s).collect())
 }
 
-fn write_switches<P: AsRef<Path>>(filename: P, switches: &[Switch]) -> Result<(), std::io::Error> {
-    // TODO: handle unwrap()
-    let json = serde_json::to_vec(switches).unwrap();
-    write_file(filename, &json)
+fn serialize_record(record: &Record, format: Format, key: Option<&Key>) -> Result<String, ()> {
+    let data = match format {
+        Format::Json => serde_json::to_vec(record).map_err(|e| format!("{}", e)),
+        Format::Binary => encoding::to_binary(&BinaryRecord::from(record)),
+    };
+
+    data.and_then(|data| encryption::encode(key, data))
+        .map(|line| line + "\n")
+        .map_err(|e| warn!("failed to serialize db record; {}", e))
+}
+
+fn deserialize_record(data: &[u8]) -> Result<Record, String> {
+    if encoding::is_binary(data) {
+        let (_, rest) = encoding::split_version(data)?;
+        encoding::deserialize::<BinaryRecord>(rest).map(Record::from)
+    } else {
+        serde_json::from_slice(data).map_err(|e| format!("{}", e))
+    }
 }
 
-fn write_file<P: AsRef<Path>>(filename: P, data: &[u8]) -> Result<(), std::io::Error> {
-    // TODO: write to temp file first
-    // TODO: explore ser::to_writer()
-    OpenOptions::new()
+/// Replaces `filename` with `data` such that a crash leaves either the old or the new contents
+/// but never a partial write. The data is written to a temporary file next to it, synced, and
+/// renamed over the original.
+pub fn write_file(filename: &Path, data: &[u8]) -> Result<(), std::io::Error> {
+    let mut tmp_name = filename.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_name = PathBuf::from(tmp_name);
+
+    let mut fh = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(filename)?
-        .write_all(data)
+        .open(&tmp_name)?;
+    fh.write_all(data)?;
+    fh.sync_all()?;
+    drop(fh);
+
+    fs::rename(&tmp_name, filename)?;
+
+    // The rename itself is only durable once the directory is synced.
+    let dir = match filename.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
 }
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::schedule::Schedule;
+use crate::{CheckinRecord, Cron, Metadata, Priority, State, Switch, Timing};
+
+/// The first byte of everything written in the binary format. Fields are written by name, so
+/// adding one with a default doesn't need a new version; only a change to how existing ones are
+/// laid out does.
+const VERSION: u8 = 1;
+
+/// How a store lays out a switch before persisting it. Both are detected when reading, JSON by its
+/// opening `{` and binary by its version byte, so the format can be changed at any time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    /// MessagePack, with timestamps as numbers rather than strings. Smaller and quicker to read
+    /// than JSON.
+    Binary,
+}
+
+/// A switch as it is laid out in the binary format. Fields missing from what was written before
+/// they were added take their defaults.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct BinarySwitch {
+    name: String,
+    deadline: (i64, u32),
+    window_start: Option<(i64, u32)>,
+    state: State,
+    paused_at: Option<(i64, u32)>,
+    cron: Option<Cron>,
+    description: Option<String>,
+    owner: Option<String>,
+    labels: BTreeMap<String, String>,
+    message: Option<String>,
+    priority: Priority,
+    callback: Option<String>,
+    depends_on: Vec<String>,
+    calendar: Option<Schedule>,
+    token_hash: Option<String>,
+    started_at: Option<(i64, u32)>,
+    max_runtime: Option<u64>,
+    last_runtime: Option<u64>,
+    deadline_after_run: Option<(i64, u32)>,
+    misses_before_alert: Option<u32>,
+    misses: u32,
+    cadence_tolerance: Option<f64>,
+    late_tolerance: Option<u64>,
+    late_since: Option<(i64, u32)>,
+    warn_before: Option<u64>,
+    warned: bool,
+    renotify_every: Option<u64>,
+    payload: Option<String>,
+    history: Vec<((i64, u32), Timing)>,
+}
+
+fn timestamp(t: &DateTime<Utc>) -> (i64, u32) {
+    (t.timestamp(), t.timestamp_subsec_nanos())
+}
+
+fn datetime((secs, nanos): (i64, u32)) -> DateTime<Utc> {
+    Utc.timestamp(secs, nanos)
+}
+
+impl From<&Switch> for BinarySwitch {
+    fn from(s: &Switch) -> Self {
+        BinarySwitch {
+            name: s.name.clone(),
+            deadline: timestamp(&s.deadline),
+            window_start: s.window_start.as_ref().map(timestamp),
+            state: s.state,
+            paused_at: s.paused_at.as_ref().map(timestamp),
+            cron: s.cron.clone(),
+            description: s.metadata.description.clone(),
+            owner: s.metadata.owner.clone(),
+            labels: s.metadata.labels.clone(),
+            message: s.metadata.message.clone(),
+            priority: s.metadata.priority,
+            callback: s.metadata.callback.clone(),
+            depends_on: s.metadata.depends_on.clone(),
+            calendar: s.metadata.calendar.clone(),
+            token_hash: s.token_hash.clone(),
+            started_at: s.started_at.as_ref().map(timestamp),
+            max_runtime: s.max_runtime,
+            last_runtime: s.last_runtime,
+            deadline_after_run: s.deadline_after_run.as_ref().map(timestamp),
+            misses_before_alert: s.misses_before_alert,
+            misses: s.misses,
+            cadence_tolerance: s.cadence_tolerance,
+            late_tolerance: s.late_tolerance,
+            late_since: s.late_since.as_ref().map(timestamp),
+            warn_before: s.warn_before,
+            warned: s.warned,
+            renotify_every: s.renotify_every,
+            payload: s.payload.clone(),
+            history: s
+                .history
+                .iter()
+                .map(|c| (timestamp(&c.at), c.timing))
+                .collect(),
+        }
+    }
+}
+
+impl From<BinarySwitch> for Switch {
+    fn from(s: BinarySwitch) -> Self {
+        Switch {
+            name: s.name,
+            deadline: datetime(s.deadline),
+            window_start: s.window_start.map(datetime),
+            state: s.state,
+            paused_at: s.paused_at.map(datetime),
+            cron: s.cron,
+            metadata: Metadata {
+                description: s.description,
+                owner: s.owner,
+                labels: s.labels,
+                message: s.message,
+                priority: s.priority,
+                callback: s.callback,
+                depends_on: s.depends_on,
+                calendar: s.calendar,
+            },
+            token_hash: s.token_hash,
+            started_at: s.started_at.map(datetime),
+            max_runtime: s.max_runtime,
+            last_runtime: s.last_runtime,
+            deadline_after_run: s.deadline_after_run.map(datetime),
+            misses_before_alert: s.misses_before_alert,
+            misses: s.misses,
+            cadence_tolerance: s.cadence_tolerance,
+            late_tolerance: s.late_tolerance,
+            late_since: s.late_since.map(datetime),
+            warn_before: s.warn_before,
+            warned: s.warned,
+            renotify_every: s.renotify_every,
+            payload: s.payload,
+            history: s
+                .history
+                .into_iter()
+                .map(|(at, timing)| CheckinRecord {
+                    at: datetime(at),
+                    timing,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `value` in the binary format, behind the version byte.
+pub fn to_binary<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut data = vec![VERSION];
+    rmp_serde::encode::write_named(&mut data, value).map_err(|e| format!("{}", e))?;
+    Ok(data)
+}
+
+/// Whether `data` is in the binary format rather than JSON.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'{')
+}
+
+/// The version byte of binary `data` and what follows it.
+pub fn split_version(data: &[u8]) -> Result<(u8, &[u8]), String> {
+    match data.split_first() {
+        Some((&version, rest)) if (1..=VERSION).contains(&version) => Ok((version, rest)),
+        Some((version, _)) => Err(format!("unsupported binary format version {}", version)),
+        None => Err("empty".to_owned()),
+    }
+}
+
+pub fn deserialize<T: DeserializeOwned>(data: &[u8]) -> Result<T, String> {
+    rmp_serde::from_slice(data).map_err(|e| format!("{}", e))
+}
+
+pub fn encode_switch(format: Format, s: &Switch) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Json => serde_json::to_vec(s).map_err(|e| format!("{}", e)),
+        Format::Binary => to_binary(&BinarySwitch::from(s)),
+    }
+}
+
+pub fn decode_switch(data: &[u8]) -> Result<Switch, String> {
+    if !is_binary(data) {
+        return serde_json::from_slice(data).map_err(|e| format!("{}", e));
+    }
+
+    let (_, rest) = split_version(data)?;
+    deserialize::<BinarySwitch>(rest).map(Switch::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn switch() -> Switch {
+        let mut s = Switch::new("backup", "2026-10-14T10:00:00.5Z".parse().unwrap());
+        s.state = State::Firing;
+        s.metadata.owner = Some("ops".to_owned());
+        s.metadata.priority = Priority::High;
+        s.metadata
+            .labels
+            .insert("env".to_owned(), "prod".to_owned());
+        s.misses = 2;
+        s.payload = Some("exit 0".to_owned());
+        s.history.push(CheckinRecord {
+            at: "2026-10-13T10:00:00Z".parse().unwrap(),
+            timing: Timing::OnTime,
+        });
+        s
+    }
+
+    fn json(s: &Switch) -> serde_json::Value {
+        serde_json::to_value(s).unwrap()
+    }
+
+    #[test]
+    fn round_trips_both_formats() {
+        let s = switch();
+        for format in &[Format::Json, Format::Binary] {
+            let data = encode_switch(*format, &s).unwrap();
+            assert_eq!(is_binary(&data), *format == Format::Binary);
+            assert_eq!(json(&decode_switch(&data).unwrap()), json(&s));
+        }
+    }
+
+    /// What was written before a field was added is read with the field's default, so adding one
+    /// doesn't need a new version.
+    #[test]
+    fn reads_missing_fields_as_their_defaults() {
+        #[derive(Serialize)]
+        struct Older {
+            name: String,
+            deadline: (i64, u32),
+        }
+
+        let data = to_binary(&Older {
+            name: "backup".to_owned(),
+            deadline: (1_791_972_000, 0),
+        })
+        .unwrap();
+
+        let s = decode_switch(&data).unwrap();
+        let expected = Switch::new("backup", "2026-10-14T10:00:00Z".parse().unwrap());
+        assert_eq!(json(&s), json(&expected));
+    }
+
+    #[test]
+    fn skips_fields_it_doesnt_know() {
+        #[derive(Serialize)]
+        struct Newer {
+            name: String,
+            deadline: (i64, u32),
+            added_later: Vec<String>,
+        }
+
+        let data = to_binary(&Newer {
+            name: "backup".to_owned(),
+            deadline: (1_791_972_000, 0),
+            added_later: vec!["x".to_owned()],
+        })
+        .unwrap();
+
+        assert_eq!(decode_switch(&data).unwrap().name, "backup");
+    }
+
+    #[test]
+    fn refuses_unknown_versions() {
+        let mut data = encode_switch(Format::Binary, &switch()).unwrap();
+        data[0] = VERSION + 1;
+        assert!(decode_switch(&data).is_err());
+        assert!(decode_switch(&[]).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use crate::stores::encoding;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// An AES-256-GCM key for encrypting switches before a store persists them. Switch names tend to
+/// describe the infrastructure they watch so they shouldn't sit in plain text on disk or in a
+/// shared Redis.
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
+impl Key {
+    /// A key from 64 hex characters, such as the output of `openssl rand -hex 32`.
+    pub fn from_hex(hex: &str) -> Result<Key, String> {
+        let bytes = hex::decode(hex.trim()).map_err(|e| format!("{}", e))?;
+        if bytes.len() != 32 {
+            return Err(format!("expected 32 bytes, got {}", bytes.len()));
+        }
+
+        let mut key = [0; 32];
+        key.copy_from_slice(&bytes);
+        Ok(Key(key))
+    }
+
+    /// `plaintext` encrypted under a random nonce, as base64 of the nonce, ciphertext and tag.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String, String> {
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(|e| format!("{}", e))?;
+
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(&nonce),
+            &[],
+            plaintext,
+            &mut tag,
+        )
+        .map_err(|e| format!("{}", e))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed.extend_from_slice(&tag);
+
+        Ok(base64::encode(&sealed))
+    }
+
+    /// The plaintext of something `seal()`ed with this key.
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>, String> {
+        let sealed = base64::decode(sealed.trim()).map_err(|e| format!("{}", e))?;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err("too short".to_owned());
+        }
+
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| "wrong key or corrupt data".to_owned())
+    }
+}
+
+/// Marks unencrypted binary data, which is kept as base64. `:` is never part of sealed data.
+const BINARY_PREFIX: &str = "b:";
+
+/// How a store decodes a persisted switch. Values that look like JSON or unencrypted binary are
+/// read as is, so a store can start encrypting without first rewriting what it already holds.
+pub fn decode(key: Option<&Key>, data: &str) -> Result<Vec<u8>, String> {
+    if data.trim_start().starts_with('{') {
+        return Ok(data.as_bytes().to_vec());
+    }
+
+    if let Some(binary) = data.strip_prefix(BINARY_PREFIX) {
+        return base64::decode(binary.trim()).map_err(|e| format!("{}", e));
+    }
+
+    match key {
+        None => Err("data is encrypted but no encryption key is configured".to_owned()),
+        Some(key) => key.open(data),
+    }
+}
+
+/// How a store encodes a serialized switch before persisting it.
+pub fn encode(key: Option<&Key>, data: Vec<u8>) -> Result<String, String> {
+    match key {
+        Some(key) => key.seal(&data),
+        None if encoding::is_binary(&data) => {
+            Ok(format!("{}{}", BINARY_PREFIX, base64::encode(&data)))
+        }
+        None => String::from_utf8(data).map_err(|e| format!("{}", e)),
+    }
+}
@@ -1,25 +1,142 @@
 use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use futures::future::ok;
-use futures::Future;
-use log::debug;
+use futures::{Future, Stream};
+use log::{debug, warn};
 use parking_lot::RwLock;
 
-use crate::stores::Store;
+use crate::stores::{Store, Updater};
 use crate::Switch;
 
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
-    switches: Arc<RwLock<BTreeMap<i64, HashMap<String, Switch>>>>,
+    switches: Arc<RwLock<Switches>>,
+    capacity: Option<Capacity>,
+}
+
+/// Switches bucketed by deadline for expiry, plus the deadline of each name so finding a switch
+/// by name doesn't mean searching every bucket.
+#[derive(Debug, Default)]
+struct Switches {
+    by_deadline: BTreeMap<i64, HashMap<String, Switch>>,
+    deadlines: HashMap<String, i64>,
+}
+
+impl Switches {
+    fn get(&self, name: &str) -> Option<&Switch> {
+        let deadline = self.deadlines.get(name)?;
+        self.by_deadline.get(deadline)?.get(name)
+    }
+
+    fn remove(&mut self, name: &str) -> Option<Switch> {
+        let deadline = self.deadlines.remove(name)?;
+
+        let bucket = self.by_deadline.get_mut(&deadline)?;
+        let s = bucket.remove(name);
+        if bucket.is_empty() {
+            self.by_deadline.remove(&deadline);
+        }
+
+        s
+    }
+
+    /// Removes up to `max` of the switches with a deadline before `when`, earliest first.
+    fn remove_expired(&mut self, when: i64, max: usize) -> Vec<Switch> {
+        let mut removed = vec![];
+
+        while removed.len() < max {
+            let names: Vec<String> = match self.by_deadline.iter().next() {
+                Some((&deadline, bucket)) if deadline < when => {
+                    bucket.keys().take(max - removed.len()).cloned().collect()
+                }
+                _ => break,
+            };
+
+            removed.extend(names.iter().filter_map(|name| self.remove(name)));
+        }
+
+        removed
+    }
+
+    /// Removes the switch with the furthest deadline.
+    fn remove_last(&mut self) -> Option<Switch> {
+        let name = self
+            .by_deadline
+            .values()
+            .next_back()
+            .and_then(|m| m.keys().next().cloned())?;
+
+        self.remove(&name)
+    }
+}
+
+/// A limit on the number of switches so that a client registering endless names can't run the
+/// process out of memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Capacity {
+    pub max: usize,
+    pub policy: FullPolicy,
+}
+
+/// What to do with a new switch when the store is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FullPolicy {
+    /// Refuse it. `has_room()` is false so it can be refused before trying.
+    Reject,
+    /// Make room by dropping the switch with the furthest deadline.
+    Evict,
 }
 
 impl MemoryStore {
     pub fn new() -> Self {
+        Self::with_capacity(None)
+    }
+
+    pub fn with_capacity(capacity: Option<Capacity>) -> Self {
         Self {
-            switches: Arc::new(RwLock::new(BTreeMap::new())),
+            switches: Arc::new(RwLock::new(Switches::default())),
+            capacity,
+        }
+    }
+
+    fn insert_locked(&self, switches: &mut Switches, s: Switch) -> Result<(), ()> {
+        debug!("inserting: {:?}", s);
+
+        // Replacing a switch, which may be in a different bucket.
+        let replaced = switches.remove(&s.name).is_some();
+
+        if let Some(capacity) = self.capacity {
+            if !replaced && switches.deadlines.len() >= capacity.max {
+                match capacity.policy {
+                    FullPolicy::Reject => {
+                        warn!("memory store is full; rejected={}", s.name);
+                        return Err(());
+                    }
+                    FullPolicy::Evict => {
+                        if let Some(evicted) = switches.remove_last() {
+                            warn!(
+                                "memory store is full; evicted={}, deadline={}",
+                                evicted.name, evicted.deadline
+                            );
+                        }
+                    }
+                }
+            }
         }
+
+        let deadline = s.wakes_at().timestamp();
+        switches.deadlines.insert(s.name.clone(), deadline);
+        switches
+            .by_deadline
+            .entry(deadline)
+            .or_default()
+            .insert(s.name.clone(), s.clone());
+
+        debug!("switches: {:?}", *switches);
+
+        Ok(())
     }
 }
 
@@ -29,6 +146,7 @@ impl Store for MemoryStore {
         let all: Vec<Switch> = self
             .switches
             .read()
+            .by_deadline
             .iter()
             .map::<Vec<Switch>, _>(|(_, m)| m.iter().map(|(_, s)| s.to_owned()).collect())
             .flatten()
@@ -37,44 +155,78 @@ impl Store for MemoryStore {
         Box::new(ok(all))
     }
 
-    fn expired(&self, when: DateTime<Utc>) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
-        let expired: Vec<i64> = self
-            .switches
-            .read()
-            .range(0..when.timestamp())
-            .map(|(&k, _)| k)
-            .collect();
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        let switches = Arc::clone(&self.switches);
 
-        let condemned = expired
-            .iter()
-            .filter_map(|k| self.switches.write().remove(k))
-            .map::<Vec<Switch>, _>(|mut m| m.drain().map(|(_, v)| v).collect())
-            .flatten()
-            .collect();
+        // The lock is only held for a batch at a time so check-ins aren't held up by a backlog.
+        let batches = futures::stream::unfold(false, move |done| {
+            if done {
+                return None;
+            }
+
+            let condemned = switches.write().remove_expired(when.timestamp(), batch);
+            let done = condemned.len() < batch;
+            Some(ok((condemned, done)))
+        });
 
-        Box::new(ok(condemned))
+        Box::new(batches.map(futures::stream::iter_ok).flatten())
     }
 
     fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
-        debug!("inserting: {:?}", s);
-
-        self.switches
-            .write()
-            .entry(s.deadline.timestamp())
-            .or_default()
-            .insert(s.name.clone(), s.clone());
-
-        debug!("switches: {:?}", self.switches);
+        let res = self.insert_locked(&mut self.switches.write(), s);
+        Box::new(futures::future::result(res))
+    }
 
-        Box::new(futures::future::ok(()))
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        let s = self.switches.read().get(name).cloned();
+        Box::new(ok(s))
     }
 
     fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
-        let s = self
-            .switches
-            .write()
-            .iter_mut()
-            .find_map(|(_, m)| m.remove(name));
+        let s = self.switches.write().remove(name);
         Box::new(ok(s))
     }
+
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let mut switches = self.switches.write();
+
+        let old = switches.remove(name);
+        let new = f(old.as_ref());
+
+        if let Some(s) = new.clone() {
+            // Can't be rejected for space as `old`, if any, was just removed.
+            if self.insert_locked(&mut switches, s).is_err() {
+                return Box::new(futures::future::err(()));
+            }
+        }
+
+        Box::new(ok((old, new)))
+    }
+
+    fn has_room(&self, name: &str) -> bool {
+        match self.capacity {
+            Some(Capacity {
+                max,
+                policy: FullPolicy::Reject,
+            }) => {
+                let switches = self.switches.read();
+                switches.deadlines.len() < max || switches.deadlines.contains_key(name)
+            }
+            _ => true,
+        }
+    }
+
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        // Expiry is by the second, so a switch is only given once the second it wakes in is over.
+        let next = self.switches.read().by_deadline.keys().next().cloned();
+        Box::new(ok(next.map(|at| Utc.timestamp(at + 1, 0))))
+    }
 }
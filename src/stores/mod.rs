@@ -1,16 +1,41 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
 
 use crate::Switch;
-use futures::Future;
+use futures::{Future, Stream};
 use log::info;
+use rusoto_core::Region;
 
 pub mod disk;
+pub mod encoding;
+pub mod encryption;
 pub mod memory;
+pub mod postgres;
 pub mod redis;
+pub mod remote;
+pub mod s3;
+pub mod store_url;
+pub mod tiered;
+pub mod watch;
+
+pub use self::redis::{Credentials, RedisStore};
+pub use disk::{Compression, DiskStore};
+pub use encoding::Format;
+pub use encryption::Key;
+pub use memory::{Capacity, FullPolicy, MemoryStore};
+pub use postgres::PostgresStore;
+pub use remote::RemoteStore;
+pub use s3::S3Store;
+pub use store_url::from_url;
+pub use tiered::TieredStore;
+pub use watch::{Change, WatchedStore};
 
-pub use self::redis::RedisStore;
-pub use disk::DiskStore;
-pub use memory::MemoryStore;
+/// Computes a switch's replacement from the switch as it is now, or `None` to remove it. It must
+/// not change the name and may be called more than once as stores that can't lock retry when the
+/// switch changes underneath them.
+pub type Updater = Arc<Fn(Option<&Switch>) -> Option<Switch> + Send + Sync>;
 
 pub trait Store {
     fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
@@ -19,9 +44,72 @@ pub trait Store {
     }
 
     fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send>;
-    fn expired(&self, when: DateTime<Utc>) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send>;
+    /// Removes the switches due before `when`, by `Switch::wakes_at()` where the store can index
+    /// them by it. They are fetched about `batch` at a time so that a large backlog doesn't have
+    /// to be held in memory all at once.
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send>;
     fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send>;
     fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send>;
+
+    /// The switch called `name`, leaving it as it is. Stores that can look up a single switch
+    /// should rather than going through every one.
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        let name = name.to_owned();
+        Box::new(
+            self.all()
+                .map(move |all| all.into_iter().find(|s| s.name == name)),
+        )
+    }
+
+    /// Atomically applies `f` to the switch called `name`. Resolves to the switch before and after.
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send>;
+
+    /// Whether inserting `name` would be refused for lack of space.
+    fn has_room(&self, _name: &str) -> bool {
+        true
+    }
+
+    /// Errors if the store can't currently be used, such as when its server is unreachable.
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Resolves once every change made so far has been written out. Stores that write each change
+    /// as it is made have nothing to do.
+    fn flush(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(futures::future::ok(()))
+    }
+
+    /// Takes the watcher lock for `holder`, or keeps it if `holder` already has it, for `ttl`.
+    /// Resolves to whether `holder` has it, so that of the replicas sharing the store only one
+    /// watches. Stores that aren't shared always give it.
+    fn lock_watcher(
+        &self,
+        _holder: &str,
+        _ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        Box::new(futures::future::ok(true))
+    }
+
+    /// Wakes the watcher whenever a switch may be due, for stores that can tell when, so that it
+    /// need only poll every so often in case a wakeup was lost. Each says a switch is due by then.
+    fn wakeups(&self) -> Option<Box<Stream<Item = DateTime<Utc>, Error = ()> + Send>> {
+        None
+    }
+
+    /// When `expired()` will next have a switch to give, or none if the store is empty, so that the
+    /// watcher can sleep until then. Stores that can't tell say now, and are polled.
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        Box::new(futures::future::ok(Some(Utc::now())))
+    }
 }
 
 #[derive(Debug)]
@@ -29,19 +117,70 @@ pub enum Stores {
     Memory(MemoryStore),
     Disk(DiskStore<MemoryStore>),
     Redis(RedisStore),
+    Postgres(PostgresStore),
+    S3(S3Store<MemoryStore>),
+    Remote(RemoteStore),
+    Tiered(TieredStore<Stores>),
 }
 
 impl Stores {
-    pub fn memory() -> Stores {
-        Stores::Memory(MemoryStore::new())
+    pub fn memory(capacity: Option<Capacity>) -> Stores {
+        Stores::Memory(MemoryStore::with_capacity(capacity))
     }
 
-    pub fn disk(filename: &str) -> Stores {
-        Stores::Disk(DiskStore::new(MemoryStore::new(), filename))
+    pub fn disk(
+        filename: &str,
+        compression: Compression,
+        key: Option<Key>,
+        format: Format,
+    ) -> Stores {
+        Stores::Disk(DiskStore::new(
+            MemoryStore::new(),
+            filename,
+            compression,
+            key,
+            format,
+        ))
     }
 
-    pub fn redis(url: &str) -> Stores {
-        Stores::Redis(RedisStore::new(url))
+    pub fn redis(
+        url: &str,
+        credentials: Credentials,
+        prefix: &str,
+        key: Option<Key>,
+        format: Format,
+        expiry_events: bool,
+    ) -> Stores {
+        Stores::Redis(RedisStore::new(
+            url,
+            credentials,
+            prefix,
+            key,
+            format,
+            expiry_events,
+        ))
+    }
+
+    pub fn postgres(url: &str) -> Stores {
+        Stores::Postgres(PostgresStore::new(url))
+    }
+
+    pub fn remote(url: &str, token: Option<String>) -> Stores {
+        Stores::Remote(RemoteStore::new(url, token))
+    }
+
+    pub fn s3(region: Region, bucket: &str, prefix: &str, index_interval: Duration) -> Stores {
+        Stores::S3(S3Store::new(
+            MemoryStore::new(),
+            region,
+            bucket,
+            prefix,
+            index_interval,
+        ))
+    }
+
+    pub fn write_behind(backing: Stores) -> Stores {
+        Stores::Tiered(TieredStore::new(backing))
     }
 }
 
@@ -51,6 +190,10 @@ impl Store for Stores {
             Stores::Memory(store) => store.init(),
             Stores::Disk(store) => store.init(),
             Stores::Redis(store) => store.init(),
+            Stores::Postgres(store) => store.init(),
+            Stores::S3(store) => store.init(),
+            Stores::Remote(store) => store.init(),
+            Stores::Tiered(store) => store.init(),
         }
     }
 
@@ -59,13 +202,25 @@ impl Store for Stores {
             Stores::Memory(store) => store.insert(s),
             Stores::Disk(store) => store.insert(s),
             Stores::Redis(store) => store.insert(s),
+            Stores::Postgres(store) => store.insert(s),
+            Stores::S3(store) => store.insert(s),
+            Stores::Remote(store) => store.insert(s),
+            Stores::Tiered(store) => store.insert(s),
         }
     }
-    fn expired(&self, when: DateTime<Utc>) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
         match self {
-            Stores::Memory(store) => store.expired(when),
-            Stores::Disk(store) => store.expired(when),
-            Stores::Redis(store) => store.expired(when),
+            Stores::Memory(store) => store.expired(when, batch),
+            Stores::Disk(store) => store.expired(when, batch),
+            Stores::Redis(store) => store.expired(when, batch),
+            Stores::Postgres(store) => store.expired(when, batch),
+            Stores::S3(store) => store.expired(when, batch),
+            Stores::Remote(store) => store.expired(when, batch),
+            Stores::Tiered(store) => store.expired(when, batch),
         }
     }
     fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
@@ -73,6 +228,10 @@ impl Store for Stores {
             Stores::Memory(store) => store.take(name),
             Stores::Disk(store) => store.take(name),
             Stores::Redis(store) => store.take(name),
+            Stores::Postgres(store) => store.take(name),
+            Stores::S3(store) => store.take(name),
+            Stores::Remote(store) => store.take(name),
+            Stores::Tiered(store) => store.take(name),
         }
     }
     fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
@@ -80,6 +239,106 @@ impl Store for Stores {
             Stores::Memory(store) => store.all(),
             Stores::Disk(store) => store.all(),
             Stores::Redis(store) => store.all(),
+            Stores::Postgres(store) => store.all(),
+            Stores::S3(store) => store.all(),
+            Stores::Remote(store) => store.all(),
+            Stores::Tiered(store) => store.all(),
+        }
+    }
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        match self {
+            Stores::Memory(store) => store.get(name),
+            Stores::Disk(store) => store.get(name),
+            Stores::Redis(store) => store.get(name),
+            Stores::Postgres(store) => store.get(name),
+            Stores::S3(store) => store.get(name),
+            Stores::Remote(store) => store.get(name),
+            Stores::Tiered(store) => store.get(name),
+        }
+    }
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        match self {
+            Stores::Memory(store) => store.update(name, f),
+            Stores::Disk(store) => store.update(name, f),
+            Stores::Redis(store) => store.update(name, f),
+            Stores::Postgres(store) => store.update(name, f),
+            Stores::S3(store) => store.update(name, f),
+            Stores::Remote(store) => store.update(name, f),
+            Stores::Tiered(store) => store.update(name, f),
+        }
+    }
+    fn has_room(&self, name: &str) -> bool {
+        match self {
+            Stores::Memory(store) => store.has_room(name),
+            Stores::Disk(store) => store.has_room(name),
+            Stores::Redis(store) => store.has_room(name),
+            Stores::Postgres(store) => store.has_room(name),
+            Stores::S3(store) => store.has_room(name),
+            Stores::Remote(store) => store.has_room(name),
+            Stores::Tiered(store) => store.has_room(name),
+        }
+    }
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        match self {
+            Stores::Memory(store) => store.health(),
+            Stores::Disk(store) => store.health(),
+            Stores::Redis(store) => store.health(),
+            Stores::Postgres(store) => store.health(),
+            Stores::S3(store) => store.health(),
+            Stores::Remote(store) => store.health(),
+            Stores::Tiered(store) => store.health(),
+        }
+    }
+    fn flush(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        match self {
+            Stores::Memory(store) => store.flush(),
+            Stores::Disk(store) => store.flush(),
+            Stores::Redis(store) => store.flush(),
+            Stores::Postgres(store) => store.flush(),
+            Stores::S3(store) => store.flush(),
+            Stores::Remote(store) => store.flush(),
+            Stores::Tiered(store) => store.flush(),
+        }
+    }
+    fn lock_watcher(
+        &self,
+        holder: &str,
+        ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        match self {
+            Stores::Memory(store) => store.lock_watcher(holder, ttl),
+            Stores::Disk(store) => store.lock_watcher(holder, ttl),
+            Stores::Redis(store) => store.lock_watcher(holder, ttl),
+            Stores::Postgres(store) => store.lock_watcher(holder, ttl),
+            Stores::S3(store) => store.lock_watcher(holder, ttl),
+            Stores::Remote(store) => store.lock_watcher(holder, ttl),
+            Stores::Tiered(store) => store.lock_watcher(holder, ttl),
+        }
+    }
+    fn wakeups(&self) -> Option<Box<Stream<Item = DateTime<Utc>, Error = ()> + Send>> {
+        match self {
+            Stores::Memory(store) => store.wakeups(),
+            Stores::Disk(store) => store.wakeups(),
+            Stores::Redis(store) => store.wakeups(),
+            Stores::Postgres(store) => store.wakeups(),
+            Stores::S3(store) => store.wakeups(),
+            Stores::Remote(store) => store.wakeups(),
+            Stores::Tiered(store) => store.wakeups(),
+        }
+    }
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        match self {
+            Stores::Memory(store) => store.next_due(),
+            Stores::Disk(store) => store.next_due(),
+            Stores::Redis(store) => store.next_due(),
+            Stores::Postgres(store) => store.next_due(),
+            Stores::S3(store) => store.next_due(),
+            Stores::Remote(store) => store.next_due(),
+            Stores::Tiered(store) => store.next_due(),
         }
     }
 }
@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use futures::future::{err, loop_fn, ok, Either, Loop};
+use futures::{Future, Stream};
+use log::{info, warn};
+use tokio_postgres::{Client, NoTls, Row};
+
+use crate::stores::{Store, Updater};
+use crate::Switch;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS condemn_switches (
+        name TEXT PRIMARY KEY,
+        deadline TIMESTAMPTZ NOT NULL,
+        switch JSONB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS condemn_switches_deadline ON condemn_switches (deadline);
+";
+
+const INSERT: &str = "
+    INSERT INTO condemn_switches (name, deadline, switch) VALUES ($1, $2, $3)
+    ON CONFLICT (name) DO UPDATE SET deadline = EXCLUDED.deadline, switch = EXCLUDED.switch
+";
+
+/// For `update()` when there was no switch to lock, so that one created in the meantime isn't
+/// overwritten.
+const CREATE: &str = "
+    INSERT INTO condemn_switches (name, deadline, switch) VALUES ($1, $2, $3)
+    ON CONFLICT (name) DO NOTHING
+";
+
+const TAKE: &str = "DELETE FROM condemn_switches WHERE name = $1 RETURNING switch";
+const EXPIRED: &str = "
+    DELETE FROM condemn_switches WHERE name IN (
+        SELECT name FROM condemn_switches WHERE deadline < $1 ORDER BY deadline LIMIT $2
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING switch
+";
+const ALL: &str = "SELECT switch FROM condemn_switches";
+const GET: &str = "SELECT switch FROM condemn_switches WHERE name = $1";
+const LOCK: &str = "SELECT switch FROM condemn_switches WHERE name = $1 FOR UPDATE";
+
+/// PostgresStore keeps one row per switch keyed by name, with when it is due in its own indexed
+/// `deadline` column so expiry is a single range delete. The full switch is kept as JSON alongside it.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    url: String,
+}
+
+impl PostgresStore {
+    pub fn new(url: &str) -> Self {
+        PostgresStore {
+            url: url.to_owned(),
+        }
+    }
+
+    fn connect(&self) -> impl Future<Item = Client, Error = ()> {
+        tokio_postgres::connect(&self.url, NoTls)
+            .map(|(client, connection)| {
+                tokio::spawn(connection.map_err(|e| warn!("postgres connection failure; {}", e)));
+                client
+            })
+            .map_err(|e| warn!("postgres failure; {}", e))
+    }
+
+    fn query_switches(
+        &self,
+        sql: &'static str,
+        expired: Option<(DateTime<Utc>, i64)>,
+        name: Option<String>,
+    ) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        let res = self
+            .connect()
+            .and_then(move |mut client| {
+                client
+                    .prepare(sql)
+                    .and_then(move |stmt| match (expired, name) {
+                        (Some((when, limit)), _) => client.query(&stmt, &[&when, &limit]).collect(),
+                        (_, Some(name)) => client.query(&stmt, &[&name]).collect(),
+                        (None, None) => client.query(&stmt, &[]).collect(),
+                    })
+                    .map_err(|e| warn!("postgres failure; {}", e))
+            })
+            .map(|rows| rows.iter().filter_map(row_to_switch).collect());
+
+        Box::new(res)
+    }
+}
+
+fn row_to_switch(row: &Row) -> Option<Switch> {
+    let json: serde_json::Value = match row.try_get(0) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("failed to read switch row; err={}", e);
+            return None;
+        }
+    };
+
+    match serde_json::from_value(json.clone()) {
+        Ok(switch) => Some(switch),
+        Err(e) => {
+            warn!("failed to deserialize switch; err={}, data={}", e, json);
+            None
+        }
+    }
+}
+
+impl Store for PostgresStore {
+    fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        info!("Creating postgres schema if needed");
+
+        let res = self.connect().and_then(|mut client| {
+            client
+                .simple_query(SCHEMA)
+                .collect()
+                .map(|_| ())
+                .map_err(|e| warn!("failed to create postgres schema; {}", e))
+        });
+
+        Box::new(res)
+    }
+
+    fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        self.query_switches(ALL, None, None)
+    }
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        let this = self.clone();
+
+        let batches = futures::stream::unfold(false, move |done| {
+            if done {
+                return None;
+            }
+
+            Some(
+                this.query_switches(EXPIRED, Some((when, batch as i64)), None)
+                    .map(move |switches| {
+                        let done = switches.len() < batch;
+                        (switches, done)
+                    }),
+            )
+        });
+
+        Box::new(batches.map(futures::stream::iter_ok).flatten())
+    }
+
+    fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
+        let json = match serde_json::to_value(&s) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("failed to serialize switch; err={}, switch={:?}", e, s);
+                return Box::new(futures::future::err(()));
+            }
+        };
+
+        let res = self.connect().and_then(move |mut client| {
+            client
+                .prepare(INSERT)
+                .and_then(move |stmt| client.execute(&stmt, &[&s.name, &s.wakes_at(), &json]))
+                .map(|_| ())
+                .map_err(|e| warn!("postgres failure; {}", e))
+        });
+
+        Box::new(res)
+    }
+
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        Box::new(
+            self.query_switches(GET, None, Some(name.to_owned()))
+                .map(|list| list.into_iter().next()),
+        )
+    }
+
+    fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        Box::new(
+            self.query_switches(TAKE, None, Some(name.to_owned()))
+                .map(|list| list.into_iter().next()),
+        )
+    }
+
+    /// Locks the row for the length of a transaction. A row that doesn't exist yet can't be locked
+    /// so creating one starts over if another was created first. Each call has its own connection
+    /// so a failure rolls back when the connection is dropped.
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let this = self.clone();
+        let name = name.to_owned();
+
+        let res = loop_fn((), move |_| {
+            let name = name.clone();
+            let f = Arc::clone(&f);
+
+            this.connect().and_then(move |mut client| {
+                client
+                    .simple_query("BEGIN")
+                    .collect()
+                    .and_then(move |_| client.prepare(LOCK).map(move |stmt| (client, stmt)))
+                    .and_then(move |(mut client, stmt)| {
+                        client
+                            .query(&stmt, &[&name])
+                            .collect()
+                            .map(move |rows| (client, rows, name))
+                    })
+                    .map_err(|e| warn!("postgres failure; {}", e))
+                    .and_then(move |(mut client, rows, name)| {
+                        let old = rows.first().and_then(row_to_switch);
+                        let new = f(old.as_ref());
+
+                        let write: Box<Future<Item = _, Error = _> + Send> = match (&old, &new) {
+                            (_, Some(s)) => {
+                                let json = match serde_json::to_value(s) {
+                                    Ok(json) => json,
+                                    Err(e) => {
+                                        warn!(
+                                            "failed to serialize switch; err={}, switch={:?}",
+                                            e, s
+                                        );
+                                        return Either::A(err(()));
+                                    }
+                                };
+                                let sql = if old.is_some() { INSERT } else { CREATE };
+                                let deadline = s.wakes_at();
+
+                                Box::new(client.prepare(sql).and_then(move |stmt| {
+                                    client
+                                        .execute(&stmt, &[&name, &deadline, &json])
+                                        .map(move |n| (client, n))
+                                }))
+                            }
+                            (Some(_), None) => {
+                                Box::new(client.prepare(TAKE).and_then(move |stmt| {
+                                    client.execute(&stmt, &[&name]).map(move |n| (client, n))
+                                }))
+                            }
+                            (None, None) => Box::new(ok((client, 1))),
+                        };
+
+                        Either::B(
+                            write
+                                .and_then(|(mut client, n)| {
+                                    client.simple_query("COMMIT").collect().map(move |_| n)
+                                })
+                                .map_err(|e| warn!("postgres failure; {}", e))
+                                .map(move |n| {
+                                    if n == 0 {
+                                        Loop::Continue(())
+                                    } else {
+                                        Loop::Break((old, new))
+                                    }
+                                }),
+                        )
+                    })
+            })
+        });
+
+        Box::new(res)
+    }
+
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let res = self.connect().and_then(|mut client| {
+            client
+                .simple_query("SELECT 1")
+                .collect()
+                .map(|_| ())
+                .map_err(|e| warn!("postgres failure; {}", e))
+        });
+
+        Box::new(res)
+    }
+}
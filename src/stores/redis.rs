@@ -1,79 +1,476 @@
-use chrono::{DateTime, Utc};
-use futures::future::err;
-use futures::Future;
-use log::warn;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use crate::stores::Store;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::{err, loop_fn, ok, Either, Loop};
+use futures::sync::mpsc::{channel, Sender};
+use futures::{Future, Stream};
+use log::{info, warn};
+use parking_lot::Mutex;
+use redis::r#async::{ConnectionLike, SharedConnection};
+use redis::{RedisError, RedisResult};
+
+use crate::stores::encoding::{self, Format};
+use crate::stores::encryption::{self, Key};
+use crate::stores::{Store, Updater};
+use crate::tunnel::{tls_tunnel, TlsConfig};
 use crate::Switch;
 
-const ORDERED_KEY: &'static str = "condemn_z";
-const SWITCH_KEY: &'static str = "condemn_h";
+/// Credentials sent with `AUTH` on every new connection. With a username this authenticates as a
+/// Redis 6 ACL user, without one it is the `requirepass` password. Kept out of the URL handed to
+/// the redis client so they don't end up in its `Debug` output.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// Splits any `user:password@` out of `url`, returning the URL without them. `self` takes
+    /// precedence over the credentials in the URL.
+    pub fn split_url(&self, url: &str) -> Result<(String, Credentials), String> {
+        let mut parsed = url::Url::parse(url).map_err(|e| format!("{}", e))?;
+
+        let username = match parsed.username() {
+            "" => None,
+            user => Some(user.to_owned()),
+        };
+        let password = parsed.password().map(|p| p.to_owned());
+
+        // Only fails for URLs without a host, which have no credentials to remove.
+        let _ = parsed.set_username("");
+        let _ = parsed.set_password(None);
+
+        let credentials = Credentials {
+            username: self.username.clone().or(username),
+            password: self.password.clone().or(password),
+        };
 
-#[derive(Debug)]
+        Ok((parsed.into_string(), credentials))
+    }
+
+    /// Authenticates `conn` if there is a password, handing the connection back for use.
+    pub fn authenticate<C>(&self, conn: C) -> Box<Future<Item = C, Error = RedisError> + Send>
+    where
+        C: ConnectionLike + Send + 'static,
+    {
+        let password = match &self.password {
+            Some(password) => password,
+            None => return Box::new(ok(conn)),
+        };
+
+        let mut auth = redis::cmd("AUTH");
+        if let Some(username) = &self.username {
+            auth.arg(username);
+        }
+        auth.arg(password);
+
+        Box::new(
+            auth.query_async::<_, redis::Value>(conn)
+                .map(|(conn, _)| conn),
+        )
+    }
+}
+
+#[derive(Clone)]
 pub struct RedisStore {
     client: redis::Client,
+    credentials: Credentials,
+    key: Option<Key>,
+    format: Format,
+    ordered_key: String,
+    switch_key: String,
+    lock_key: String,
+    due_key: String,
+    expiry_events: bool,
+    conn: Arc<Mutex<Option<SharedConnection>>>,
+}
+
+impl std::fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("client", &self.client)
+            .field("credentials", &self.credentials)
+            .field("key", &self.key)
+            .field("format", &self.format)
+            .field("ordered_key", &self.ordered_key)
+            .field("switch_key", &self.switch_key)
+            .field("lock_key", &self.lock_key)
+            .field("due_key", &self.due_key)
+            .field("expiry_events", &self.expiry_events)
+            .field("connected", &self.conn.lock().is_some())
+            .finish()
+    }
 }
 
 /// RedisStore keeps a sorted set of names for expiry and a hash map of the names to json
 /// serialized objects. When items are removed from the sorted set the names are looked up in the
 /// hash map. If the name doesn't exist there then it is ignored. In this way Switches are not
 /// leaked as long as _something_ is calling expired() on a regular basis.
+///
+/// The keys are `<prefix>_z` and `<prefix>_h` so instances with different prefixes can share a
+/// database. Replicas sharing the keys take turns watching with the lock `<prefix>_lock`.
+///
+/// With `expiry_events` each switch also has a key `<prefix>_t:<name>` that expires when it is
+/// due. The watcher is woken by Redis telling of those expiring rather than having to ask every
+/// second, which requires `notify-keyspace-events` to include `Ex`.
+///
+/// All calls share a single multiplexed connection which is opened on first use and re-opened on
+/// the next call after it fails.
 impl RedisStore {
-    pub fn new(url: &str) -> Self {
+    pub fn new(
+        url: &str,
+        credentials: Credentials,
+        prefix: &str,
+        key: Option<Key>,
+        format: Format,
+        expiry_events: bool,
+    ) -> Self {
         RedisStore {
             client: redis::Client::open(url).unwrap(),
+            credentials,
+            key,
+            format,
+            ordered_key: format!("{}_z", prefix),
+            switch_key: format!("{}_h", prefix),
+            lock_key: format!("{}_lock", prefix),
+            due_key: format!("{}_t:", prefix),
+            expiry_events,
+            conn: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn connection(&self) -> Box<Future<Item = SharedConnection, Error = ()> + Send> {
+        if let Some(conn) = self.conn.lock().clone() {
+            return Box::new(ok(conn));
         }
+
+        let cache = Arc::clone(&self.conn);
+        let credentials = self.credentials.clone();
+
+        Box::new(
+            self.client
+                .get_shared_async_connection()
+                .and_then(move |conn| credentials.authenticate(conn))
+                .map(move |conn| {
+                    info!("connected to redis");
+                    *cache.lock() = Some(conn.clone());
+                    conn
+                })
+                .map_err(|e| warn!("redis failure; {:?}", e)),
+        )
+    }
+
+    /// Runs `f` on the shared connection. The connection is dropped if it has failed so that the
+    /// next query reconnects.
+    fn query<T, F, R>(&self, f: F) -> Box<Future<Item = T, Error = ()> + Send>
+    where
+        T: Send + 'static,
+        F: FnOnce(SharedConnection) -> R + Send + 'static,
+        R: Future<Item = (SharedConnection, T), Error = RedisError> + Send + 'static,
+    {
+        let cache = Arc::clone(&self.conn);
+
+        Box::new(self.connection().and_then(move |conn| {
+            f(conn).map(|(_, v)| v).map_err(move |e| {
+                warn!("redis failure; {:?}", e);
+                if e.is_io_error() || e.is_connection_dropped() || e.is_timeout() {
+                    *cache.lock() = None;
+                }
+            })
+        }))
+    }
+
+    /// The key that expires when `name` is due.
+    fn due_key(&self, name: &str) -> String {
+        format!("{}{}", self.due_key, name)
+    }
+
+    /// `SET`s the key that expires when `s` is due, if the store uses them.
+    fn set_due(&self, s: &Switch) -> Option<redis::Cmd> {
+        if !self.expiry_events {
+            return None;
+        }
+
+        let mut set = redis::cmd("SET");
+        set.arg(self.due_key(&s.name));
+        set.arg("");
+        set.arg("PX");
+        set.arg(due_in_millis(s.wakes_at().timestamp()));
+        Some(set)
+    }
+
+    fn take_multi(&self, names: Vec<String>) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        // HMGET and friends refuse an empty list of fields.
+        if names.is_empty() {
+            return Box::new(ok(vec![]));
+        }
+
+        let mut hmget = redis::cmd("HMGET");
+        hmget.arg(&self.switch_key);
+        hmget.arg(&names[..]);
+
+        let mut hdel = redis::cmd("HDEL");
+        hdel.arg(&self.switch_key);
+        hdel.arg(&names[..]);
+
+        let mut zrem = redis::cmd("ZREM");
+        zrem.arg(&self.ordered_key);
+        zrem.arg(&names[..]);
+
+        let mut p = redis::pipe();
+        p.atomic();
+        p.add_command(&hmget);
+        p.add_command(&hdel).ignore();
+        p.add_command(&zrem).ignore();
+
+        if self.expiry_events {
+            let mut del = redis::cmd("DEL");
+            for name in &names {
+                del.arg(self.due_key(name));
+            }
+            p.add_command(&del).ignore();
+        }
+
+        let key = self.key.clone();
+
+        let res = self.query(move |conn| p.query_async(conn)).map(
+            move |(jsons,): (Vec<Option<String>>,)| {
+                jsons
+                    .iter()
+                    .filter_map(|s| s.as_ref().and_then(|s| deserialize_switch(key.as_ref(), s)))
+                    .collect()
+            },
+        );
+
+        Box::new(res)
     }
 }
 
-fn take_multi(
-    conn: redis::r#async::Connection,
-    names: &[String],
-) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
-    let mut hmget = redis::cmd("HMGET");
-    hmget.arg(SWITCH_KEY);
-    hmget.arg(names.clone());
-
-    let mut hdel = redis::cmd("HDEL");
-    hdel.arg(SWITCH_KEY);
-    hdel.arg(names.clone());
-
-    let mut zrem = redis::cmd("ZREM");
-    zrem.arg(ORDERED_KEY);
-    zrem.arg(names);
-
-    let mut p = redis::pipe();
-    p.atomic();
-    p.add_command(&hmget);
-    p.add_command(&hdel).ignore();
-    p.add_command(&zrem).ignore();
-
-    let res = p
-        .query_async(conn)
-        .map_err(|e| warn!("redis failure; {:?}", e))
-        .map(|(_, jsons): (_, Vec<String>)| {
-            jsons
-                .iter()
-                .filter_map(|s| deserialize_switch(&s))
-                .collect()
-        });
+/// The URL to hand to the redis client. `rediss://` URLs are rewritten to go through a local TLS
+/// tunnel since the client can't do TLS itself, so they mustn't have credentials in them; split
+/// those out first.
+pub fn connection_url(url: &str, tls: &TlsConfig) -> Result<String, String> {
+    if !url.starts_with("rediss://") {
+        return Ok(url.to_owned());
+    }
+
+    let parsed = url::Url::parse(url).map_err(|e| format!("{}", e))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| "rediss:// URL requires a host".to_owned())?;
+    let port = parsed.port().unwrap_or(6379);
+
+    let socket = tls_tunnel(host, port, tls)?;
+
+    let mut tunneled = url::Url::parse("redis+unix:///").expect("a valid URL");
+    tunneled.set_path(
+        socket
+            .to_str()
+            .ok_or_else(|| format!("{:?}; not UTF-8", socket))?,
+    );
+    match parsed.path().trim_matches('/') {
+        "" => (),
+        db => {
+            tunneled.query_pairs_mut().append_pair("db", db);
+        }
+    }
+
+    Ok(tunneled.into_string())
+}
+
+/// Whether `url` is one the redis client can be given, without connecting or starting a tunnel.
+pub fn check_url(url: &str) -> Result<(), String> {
+    // TLS is handled by us, see `connection_url()`.
+    let url = url.replacen("rediss://", "redis://", 1);
+
+    match redis::parse_redis_url(&url) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("unknown format; See help.".to_owned()),
+    }
+}
+
+/// Extends the lock `KEYS[1]` by `ARGV[2]` milliseconds if `ARGV[1]` holds it, or takes it for
+/// `ARGV[1]` if nothing does. Returns 1 if `ARGV[1]` holds it.
+const LOCK_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return 1
+end
+return 0
+"#;
+
+/// Replaces a switch only if its serialized value is still the one that was read; an empty value
+/// stands for no switch. Resolves to 1 if it was replaced, 0 if the switch changed in between.
+///
+/// KEYS: switch hash, ordered set, due key. ARGV: name, value read, new value, new deadline,
+/// milliseconds until the due key expires or empty without one.
+const UPDATE_SCRIPT: &str = r#"
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+if (current or '') ~= ARGV[2] then
+    return 0
+end
+if ARGV[3] == '' then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+    redis.call('ZREM', KEYS[2], ARGV[1])
+    redis.call('DEL', KEYS[3])
+else
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+    redis.call('ZADD', KEYS[2], ARGV[4], ARGV[1])
+    if ARGV[5] ~= '' then
+        redis.call('SET', KEYS[3], '', 'PX', ARGV[5])
+    end
+end
+return 1
+"#;
+
+/// Takes up to `ARGV[2]` switches due before `ARGV[1]`, a `ZRANGEBYSCORE` max, resolving to their
+/// serialized values, `nil` for any missing from the hash.
+///
+/// KEYS: switch hash, ordered set. ARGV: max, batch size, prefix of the due keys to delete or
+/// empty without them.
+const TAKE_EXPIRED_SCRIPT: &str = r#"
+local names = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+local values = {}
+for i, name in ipairs(names) do
+    values[i] = redis.call('HGET', KEYS[1], name)
+    redis.call('HDEL', KEYS[1], name)
+    redis.call('ZREM', KEYS[2], name)
+    if ARGV[3] ~= '' then
+        redis.call('DEL', ARGV[3] .. name)
+    end
+end
+return values
+"#;
+
+/// Milliseconds until the due key of a switch waking at `wakes_at` is to expire. That is just
+/// after that second, as `expired()` takes switches due before the second it is called in.
+fn due_in_millis(wakes_at: i64) -> i64 {
+    let due = (wakes_at + 1) * 1000;
+    // `PX` refuses anything but a positive number, and a key expiring at once is still an event.
+    (due - Utc::now().timestamp_millis()).max(1)
+}
+
+/// Whether Redis is set to send the expiry events `notify-keyspace-events` describes.
+fn sends_expiry_events(flags: &str) -> bool {
+    flags.contains('E') && (flags.contains('x') || flags.contains('A'))
+}
+
+/// Tells `due` of every key under `due_key` expiring, on a connection of its own, until `due` is
+/// dropped. Redis doesn't keep events for a connection that isn't subscribed, so `due` is also
+/// told each time it (re)subscribes in case it missed any.
+fn subscribe(
+    client: redis::Client,
+    credentials: Credentials,
+    due_key: String,
+    mut due: Sender<DateTime<Utc>>,
+) {
+    let db = redis::ConnectionLike::get_db(&client);
+    let channel = format!("__keyevent@{}__:expired", db);
 
-    Box::new(res)
+    loop {
+        match listen(&client, &credentials, &channel, &due_key, &mut due) {
+            Ok(()) => return,
+            Err(e) => warn!("redis expiry events failure, resubscribing; {:?}", e),
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
 }
 
-fn deserialize_switch(json: &str) -> Option<Switch> {
-    match serde_json::from_str(json) {
+/// One connection's worth of `subscribe`, returning once `due` is dropped.
+fn listen(
+    client: &redis::Client,
+    credentials: &Credentials,
+    channel: &str,
+    due_key: &str,
+    due: &mut Sender<DateTime<Utc>>,
+) -> RedisResult<()> {
+    let mut conn = client.get_connection()?;
+
+    if let Some(password) = &credentials.password {
+        let mut auth = redis::cmd("AUTH");
+        if let Some(username) = &credentials.username {
+            auth.arg(username);
+        }
+        auth.arg(password);
+        auth.query::<()>(&conn)?;
+    }
+
+    // Managed Redis often refuses `CONFIG`, in which case the events may well be set up anyway.
+    let config: RedisResult<Vec<String>> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query(&conn);
+    match config {
+        Ok(config) => {
+            if !config
+                .get(1)
+                .is_some_and(|flags| sends_expiry_events(flags))
+            {
+                warn!(
+                    "redis isn't sending expiry events, switches won't notify until the watcher \
+                     next polls; set notify-keyspace-events to include `Ex`"
+                );
+            }
+        }
+        Err(e) => info!("couldn't check that redis sends expiry events; {:?}", e),
+    }
+
+    let mut pubsub = conn.as_pubsub();
+    pubsub.subscribe(channel)?;
+    info!("subscribed to redis expiry events; channel={}", channel);
+
+    // Whether `due` is still there to be told. A full channel already has a wakeup waiting.
+    let wake = |due: &mut Sender<DateTime<Utc>>| match due.try_send(Utc::now()) {
+        Ok(()) => true,
+        Err(e) => !e.is_disconnected(),
+    };
+
+    if !wake(due) {
+        return Ok(());
+    }
+
+    loop {
+        let msg = pubsub.get_message()?;
+        if msg.get_payload_bytes().starts_with(due_key.as_bytes()) && !wake(due) {
+            return Ok(());
+        }
+    }
+}
+
+fn deserialize_switch(key: Option<&Key>, data: &str) -> Option<Switch> {
+    let decoded = match encryption::decode(key, data) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("failed to decrypt switch; err={}", e);
+            return None;
+        }
+    };
+
+    match encoding::decode_switch(&decoded) {
         Ok(switch) => Some(switch),
         Err(e) => {
-            warn!("failed to deserialize switch; err={}, data={}", e, json);
+            warn!("failed to deserialize switch; err={}, data={}", e, data);
             None
         }
     }
 }
 
-fn serialize_switch(s: &Switch) -> Option<String> {
-    match serde_json::to_string(s) {
-        Ok(json) => Some(json),
+fn serialize_switch(key: Option<&Key>, format: Format, s: &Switch) -> Option<String> {
+    match encoding::encode_switch(format, s).and_then(|data| encryption::encode(key, data)) {
+        Ok(data) => Some(data),
         Err(e) => {
             warn!("failed to serialize switch; err={}, switch={:?}", e, s);
             None
@@ -82,91 +479,303 @@ fn serialize_switch(s: &Switch) -> Option<String> {
 }
 
 impl Store for RedisStore {
+    /// With `expiry_events`, gives the switches that don't have one a due key, such as those
+    /// stored before the store used them.
+    fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        if !self.expiry_events {
+            return Box::new(ok(()));
+        }
+
+        let mut zrange = redis::cmd("ZRANGE");
+        zrange.arg(&self.ordered_key);
+        zrange.arg(0);
+        zrange.arg(-1);
+        zrange.arg("WITHSCORES");
+
+        let this = self.clone();
+
+        let res = self.query(move |conn| zrange.query_async(conn)).and_then(
+            move |scored: Vec<String>| {
+                // An empty pipeline has no answer to wait for.
+                if scored.is_empty() {
+                    return Either::A(ok(()));
+                }
+
+                let mut p = redis::pipe();
+                for pair in scored.chunks(2) {
+                    if let [name, score] = pair {
+                        let wakes_at = score.parse::<f64>().unwrap_or(0.0) as i64;
+                        p.cmd("SET")
+                            .arg(this.due_key(name))
+                            .arg("")
+                            .arg("PX")
+                            .arg(due_in_millis(wakes_at))
+                            .arg("NX")
+                            .ignore();
+                    }
+                }
+
+                info!("checked the due keys of {} switches", scored.len() / 2);
+                Either::B(this.query(move |conn| p.query_async::<_, ()>(conn)))
+            },
+        );
+
+        Box::new(res)
+    }
+
     fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
-        let mut hgetall = redis::cmd("HGETALL");
-        hgetall.arg(SWITCH_KEY);
+        let mut hvals = redis::cmd("HVALS");
+        hvals.arg(&self.switch_key);
+
+        let key = self.key.clone();
 
         let res = self
-            .client
-            .get_async_connection()
-            .and_then(move |conn| hgetall.query_async(conn))
-            .map_err(|e| warn!("redis failure; {:?}", e))
-            .map(|(_, jsons): (_, Vec<String>)| {
+            .query(move |conn| hvals.query_async(conn))
+            .map(move |jsons: Vec<String>| {
                 jsons
                     .iter()
-                    .filter_map(|s| deserialize_switch(&s))
+                    .filter_map(|s| deserialize_switch(key.as_ref(), &s))
                     .collect()
             });
 
         Box::new(res)
     }
 
-    fn expired(&self, when: DateTime<Utc>) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
-        let mut zrange = redis::cmd("ZRANGEBYSCORE");
-        zrange.arg(ORDERED_KEY);
-        zrange.arg("-inf");
-        zrange.arg(when.timestamp());
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        // Exclusive of `when` to match the other stores.
+        let max = format!("({}", when.timestamp());
 
-        let mut zrem = redis::cmd("ZREMRANGEBYSCORE");
-        zrem.arg(ORDERED_KEY);
-        zrem.arg("-inf");
-        zrem.arg(when.timestamp());
+        let this = self.clone();
 
-        let mut expired = redis::pipe();
-        expired.atomic();
-        expired.add_command(&zrange);
-        expired.add_command(&zrem).ignore();
+        // Each batch is found and taken from both the sorted set and the hash map at once by the
+        // script, so a switch checked in meanwhile is left alone.
+        let batches = futures::stream::unfold(false, move |done| {
+            if done {
+                return None;
+            }
 
-        let res = self
-            .client
-            .get_async_connection()
-            .and_then(move |conn| expired.query_async(conn))
-            .map_err(|e| warn!("redis failure; {:?}", e))
-            .and_then(move |(conn, expired): (_, Vec<String>)| take_multi(conn, &expired));
+            let mut eval = redis::cmd("EVAL");
+            eval.arg(TAKE_EXPIRED_SCRIPT);
+            eval.arg(2);
+            eval.arg(&this.switch_key);
+            eval.arg(&this.ordered_key);
+            eval.arg(&max);
+            eval.arg(batch);
+            eval.arg(if this.expiry_events {
+                this.due_key.as_str()
+            } else {
+                ""
+            });
 
-        Box::new(res)
+            let key = this.key.clone();
+
+            Some(this.query(move |conn| eval.query_async(conn)).map(
+                move |values: Vec<Option<String>>| {
+                    let done = values.len() < batch;
+                    let switches = values
+                        .iter()
+                        .filter_map(|s| {
+                            s.as_ref().and_then(|s| deserialize_switch(key.as_ref(), s))
+                        })
+                        .collect::<Vec<_>>();
+                    (switches, done)
+                },
+            ))
+        });
+
+        Box::new(batches.map(futures::stream::iter_ok).flatten())
     }
 
     fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
-        let serialized = match serialize_switch(&s) {
+        let serialized = match serialize_switch(self.key.as_ref(), self.format, &s) {
             Some(json) => json,
             None => return Box::new(err(())),
         };
 
         let mut hset = redis::cmd("HSET");
-        hset.arg(SWITCH_KEY);
+        hset.arg(&self.switch_key);
+        hset.arg(&s.name);
         hset.arg(serialized);
 
         let mut zadd = redis::cmd("ZADD");
-        zadd.arg(ORDERED_KEY);
-        zadd.arg(s.deadline.timestamp());
-        zadd.arg(s.name);
+        zadd.arg(&self.ordered_key);
+        zadd.arg(s.wakes_at().timestamp());
+        zadd.arg(&s.name);
 
         let mut p = redis::pipe();
         p.atomic();
-        p.add_command(&hset);
-        p.add_command(&zadd);
+        p.add_command(&hset).ignore();
+        p.add_command(&zadd).ignore();
+
+        if let Some(set) = self.set_due(&s) {
+            p.add_command(&set).ignore();
+        }
 
         let res = self
-            .client
-            .get_async_connection()
-            .and_then(move |conn| p.query_async::<_, (i64, i64)>(conn))
-            .map_err(|e| warn!("redis failure; {:?}", e))
+            .query(move |conn| p.query_async::<_, ()>(conn))
             .map(|_| ());
 
         Box::new(res)
     }
 
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        let mut hget = redis::cmd("HGET");
+        hget.arg(&self.switch_key);
+        hget.arg(name);
+
+        let key = self.key.clone();
+
+        let res =
+            self.query(move |conn| hget.query_async(conn))
+                .map(move |data: Option<String>| {
+                    data.and_then(|data| deserialize_switch(key.as_ref(), &data))
+                });
+
+        Box::new(res)
+    }
+
     fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        Box::new(
+            self.take_multi(vec![name.to_owned()])
+                .map(|list| list.into_iter().next()),
+        )
+    }
+
+    /// Reads the switch, applies `f` and writes the result with a script that checks nothing wrote
+    /// the switch in between, starting over if something did.
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let this = self.clone();
         let name = name.to_owned();
 
+        let res = loop_fn((), move |_| {
+            let mut hget = redis::cmd("HGET");
+            hget.arg(&this.switch_key);
+            hget.arg(&name);
+
+            let this = this.clone();
+            let name = name.clone();
+            let f = Arc::clone(&f);
+
+            this.query(move |conn| hget.query_async(conn)).and_then(
+                move |current: Option<String>| {
+                    let key = this.key.as_ref();
+                    let old = current
+                        .as_ref()
+                        .and_then(|data| deserialize_switch(key, data));
+                    let new = f(old.as_ref());
+
+                    let serialized = match &new {
+                        None => String::new(),
+                        Some(s) => match serialize_switch(key, this.format, s) {
+                            Some(data) => data,
+                            None => return Either::A(err(())),
+                        },
+                    };
+
+                    let mut eval = redis::cmd("EVAL");
+                    eval.arg(UPDATE_SCRIPT);
+                    eval.arg(3);
+                    eval.arg(&this.switch_key);
+                    eval.arg(&this.ordered_key);
+                    eval.arg(this.due_key(&name));
+                    eval.arg(&name);
+                    eval.arg(current.unwrap_or_default());
+                    eval.arg(serialized);
+                    eval.arg(new.as_ref().map_or(0, |s| s.wakes_at().timestamp()));
+                    eval.arg(match &new {
+                        Some(s) if this.expiry_events => {
+                            due_in_millis(s.wakes_at().timestamp()).to_string()
+                        }
+                        _ => String::new(),
+                    });
+
+                    Either::B(this.query(move |conn| eval.query_async(conn)).map(
+                        move |replaced: i64| {
+                            if replaced == 1 {
+                                Loop::Break((old, new))
+                            } else {
+                                info!("switch changed during update; retrying, name={}", name);
+                                Loop::Continue(())
+                            }
+                        },
+                    ))
+                },
+            )
+        });
+
+        Box::new(res)
+    }
+
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let ping = redis::cmd("PING");
+
         Box::new(
-            self.client
-                .get_async_connection()
-                .map_err(|e| warn!("redis failure; {:?}", e))
-                .and_then(move |conn| {
-                    take_multi(conn, &[name]).map(|list| list.into_iter().next())
-                }),
+            self.query(move |conn| ping.query_async::<_, String>(conn))
+                .map(|_| ()),
+        )
+    }
+
+    fn lock_watcher(
+        &self,
+        holder: &str,
+        ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        let mut eval = redis::cmd("EVAL");
+        eval.arg(LOCK_SCRIPT);
+        eval.arg(1);
+        eval.arg(&self.lock_key);
+        eval.arg(holder);
+        eval.arg(ttl.as_millis() as u64);
+
+        Box::new(
+            self.query(move |conn| eval.query_async::<_, i64>(conn))
+                .map(|held| held == 1),
         )
     }
+
+    fn wakeups(&self) -> Option<Box<Stream<Item = DateTime<Utc>, Error = ()> + Send>> {
+        if !self.expiry_events {
+            return None;
+        }
+
+        let (due, wakeups) = channel(1);
+        let client = self.client.clone();
+        let credentials = self.credentials.clone();
+        let due_key = self.due_key.clone();
+        thread::spawn(move || subscribe(client, credentials, due_key, due));
+
+        Some(Box::new(wakeups))
+    }
+
+    /// Without expiry events the switches other replicas insert would go unseen until then, so
+    /// the watcher polls.
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        if !self.expiry_events {
+            return Box::new(ok(Some(Utc::now())));
+        }
+
+        let mut zrange = redis::cmd("ZRANGE");
+        zrange.arg(&self.ordered_key);
+        zrange.arg(0);
+        zrange.arg(0);
+        zrange.arg("WITHSCORES");
+
+        // Expiry is exclusive of the second it is checked in.
+        let res = self
+            .query(move |conn| zrange.query_async(conn))
+            .map(|scored: Vec<String>| {
+                let score = scored.get(1)?.parse::<f64>().ok()?;
+                Some(Utc.timestamp(score as i64 + 1, 0))
+            });
+
+        Box::new(res)
+    }
 }
@@ -0,0 +1,268 @@
+use chrono::{DateTime, Utc};
+use futures::future::{err, loop_fn, ok, Either, Loop};
+use futures::{Future, Stream};
+use log::{info, warn};
+use reqwest::header::{HeaderValue, ETAG, IF_MATCH, IF_NONE_MATCH};
+use reqwest::r#async::{Client, RequestBuilder, Response};
+use reqwest::{Method, StatusCode};
+use serde_derive::Serialize;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use crate::stores::{Store, Updater};
+use crate::Switch;
+
+/// RemoteStore keeps switches in an HTTP service so that condemn can sit on storage it doesn't
+/// support itself. Switches are sent and received as the same JSON as `GET /`. Relative to the
+/// base URL the service answers:
+///
+/// - `GET switches`: every switch, as an array.
+/// - `GET switches/:name`: the switch with an `ETag`, or 404.
+/// - `PUT switches/:name`: create or replace the switch. With `If-Match` only if the switch is
+///   still that version and with `If-None-Match: *` only if there is no such switch, otherwise
+///   412.
+/// - `DELETE switches/:name`: remove the switch and answer with it, or 404. `If-Match` as `PUT`.
+/// - `POST expired` with `{"before": <time>, "limit": <n>}`: remove and answer with up to `limit`
+///   switches whose deadline is before `before`, as an array. Fewer than `limit` means that
+///   was the last of them.
+/// - `GET health`: 2xx while the service can serve the rest.
+///
+/// With a token every request carries it as `Authorization: Bearer <token>`.
+#[derive(Debug, Clone)]
+pub struct RemoteStore {
+    client: Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ExpiredRequest {
+    before: DateTime<Utc>,
+    limit: usize,
+}
+
+/// Whether a conditional write should replace the switch as it was read or create one.
+enum Expect {
+    Version(HeaderValue),
+    Absent,
+}
+
+impl RemoteStore {
+    pub fn new(base_url: &str, token: Option<String>) -> Self {
+        RemoteStore {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, &format!("{}/{}", self.base_url, path));
+
+        match &self.token {
+            None => request,
+            Some(token) => request.bearer_auth(token),
+        }
+    }
+
+    fn switch_path(name: &str) -> String {
+        format!(
+            "switches/{}",
+            utf8_percent_encode(name, PATH_SEGMENT_ENCODE_SET)
+        )
+    }
+
+    /// Sends `request`, resolving to the response if its status is one of `allowed` or a success.
+    fn send(
+        request: RequestBuilder,
+        allowed: &'static [StatusCode],
+    ) -> impl Future<Item = Response, Error = ()> {
+        request
+            .send()
+            .map_err(|e| warn!("remote store failure; {}", e))
+            .and_then(move |res| {
+                if res.status().is_success() || allowed.contains(&res.status()) {
+                    Ok(res)
+                } else {
+                    warn!(
+                        "remote store failure; url={}, status={}",
+                        res.url(),
+                        res.status()
+                    );
+                    Err(())
+                }
+            })
+    }
+
+    fn json<T: 'static + serde::de::DeserializeOwned + Send>(
+        mut res: Response,
+    ) -> impl Future<Item = T, Error = ()> {
+        res.json()
+            .map_err(|e| warn!("remote store sent invalid json; {}", e))
+    }
+
+    /// The switch and its version, if there is one.
+    fn get_versioned(
+        &self,
+        name: &str,
+    ) -> impl Future<Item = Option<(Switch, HeaderValue)>, Error = ()> {
+        let request = self.request(Method::GET, &Self::switch_path(name));
+
+        Self::send(request, &[StatusCode::NOT_FOUND]).and_then(|res| {
+            if res.status() == StatusCode::NOT_FOUND {
+                return Either::A(ok(None));
+            }
+
+            match res.headers().get(ETAG).cloned() {
+                None => {
+                    warn!(
+                        "remote store sent a switch without an etag; url={}",
+                        res.url()
+                    );
+                    Either::A(err(()))
+                }
+                Some(etag) => Either::B(Self::json(res).map(move |s| Some((s, etag)))),
+            }
+        })
+    }
+
+    /// Writes `new` in place of the switch called `name` if it is still as `expect`. Resolves to
+    /// false if it has changed.
+    fn write_if(
+        &self,
+        name: &str,
+        expect: Expect,
+        new: Option<&Switch>,
+    ) -> impl Future<Item = bool, Error = ()> {
+        let path = Self::switch_path(name);
+
+        let request = match new {
+            Some(s) => self.request(Method::PUT, &path).json(s),
+            None => self.request(Method::DELETE, &path),
+        };
+
+        let request = match expect {
+            Expect::Version(etag) => request.header(IF_MATCH, etag),
+            Expect::Absent => request.header(IF_NONE_MATCH, "*"),
+        };
+
+        Self::send(
+            request,
+            &[StatusCode::PRECONDITION_FAILED, StatusCode::NOT_FOUND],
+        )
+        .map(|res| res.status().is_success())
+    }
+}
+
+impl Store for RemoteStore {
+    fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        let request = self.request(Method::GET, "switches");
+
+        Box::new(Self::send(request, &[]).and_then(Self::json))
+    }
+
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        let this = self.clone();
+
+        let batches =
+            futures::stream::unfold(false, move |done| {
+                if done {
+                    return None;
+                }
+
+                let request = this.request(Method::POST, "expired").json(&ExpiredRequest {
+                    before: when,
+                    limit: batch,
+                });
+
+                Some(Self::send(request, &[]).and_then(Self::json).map(
+                    move |switches: Vec<Switch>| {
+                        let done = switches.len() < batch;
+                        (switches, done)
+                    },
+                ))
+            });
+
+        Box::new(batches.map(futures::stream::iter_ok).flatten())
+    }
+
+    fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
+        let request = self
+            .request(Method::PUT, &Self::switch_path(&s.name))
+            .json(&s);
+
+        Box::new(Self::send(request, &[]).map(|_| ()))
+    }
+
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        Box::new(self.get_versioned(name).map(|s| s.map(|(s, _)| s)))
+    }
+
+    fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        let request = self.request(Method::DELETE, &Self::switch_path(name));
+
+        Box::new(
+            Self::send(request, &[StatusCode::NOT_FOUND]).and_then(|res| {
+                if res.status() == StatusCode::NOT_FOUND {
+                    Either::A(ok(None))
+                } else {
+                    Either::B(Self::json(res).map(Some))
+                }
+            }),
+        )
+    }
+
+    /// Reads the switch and writes the result on the condition that the version read is still
+    /// current, starting over if it isn't.
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let this = self.clone();
+        let name = name.to_owned();
+
+        let res = loop_fn((), move |_| {
+            let this = this.clone();
+            let name = name.clone();
+            let f = f.clone();
+
+            this.get_versioned(&name).and_then(move |current| {
+                let (old, expect) = match current {
+                    Some((s, etag)) => (Some(s), Expect::Version(etag)),
+                    None => (None, Expect::Absent),
+                };
+                let new = f(old.as_ref());
+
+                if old.is_none() && new.is_none() {
+                    return Either::A(ok(Loop::Break((old, new))));
+                }
+
+                Either::B(
+                    this.write_if(&name, expect, new.as_ref())
+                        .map(move |written| {
+                            if written {
+                                Loop::Break((old, new))
+                            } else {
+                                info!("switch changed during update; retrying, name={}", name);
+                                Loop::Continue(())
+                            }
+                        }),
+                )
+            })
+        });
+
+        Box::new(res)
+    }
+
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let request = self.request(Method::GET, "health");
+
+        Box::new(Self::send(request, &[]).map(|_| ()))
+    }
+}
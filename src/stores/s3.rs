@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::{ok, Either};
+use futures::{Future, Stream};
+use log::{info, warn};
+use parking_lot::Mutex;
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadBucketRequest, ListObjectsV2Request,
+    PutObjectRequest, S3Client, S3,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::stores::{Store, Updater};
+use crate::Switch;
+
+/// S3Store keeps one object per switch under `<prefix>switches/` and serves reads from the
+/// wrapped store. Every so often the whole set is also written to `<prefix>index.json` so that
+/// loading on start up only needs to fetch the switches that changed since the index was written.
+/// Suited to very low traffic deployments that can live with S3's consistency model.
+#[derive(Clone)]
+pub struct S3Store<S: Store> {
+    store: S,
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+    index_interval: Duration,
+    last_index: Arc<Mutex<Instant>>,
+}
+
+/// The contents of `index.json`. `snapshot` is taken before reading the switches so any object
+/// modified after it may be newer than its copy in the index.
+#[derive(Serialize, Deserialize)]
+struct Index {
+    snapshot: DateTime<Utc>,
+    switches: Vec<Switch>,
+}
+
+impl<S: Store + std::fmt::Debug> std::fmt::Debug for S3Store<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("S3Store")
+            .field("store", &self.store)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl<S: 'static + Clone + Store + Send + Sync> S3Store<S> {
+    pub fn new(
+        store: S,
+        region: Region,
+        bucket: &str,
+        prefix: &str,
+        index_interval: Duration,
+    ) -> Self {
+        Self {
+            store,
+            client: S3Client::new(region),
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            index_interval,
+            last_index: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    fn switches_prefix(&self) -> String {
+        format!("{}switches/", self.prefix)
+    }
+
+    fn switch_key(&self, name: &str) -> String {
+        format!("{}{}.json", self.switches_prefix(), name)
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}index.json", self.prefix)
+    }
+
+    fn put(&self, key: String, body: Vec<u8>) -> impl Future<Item = (), Error = ()> {
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.clone(),
+                body: Some(body.into()),
+                content_type: Some("application/json".to_owned()),
+                ..Default::default()
+            })
+            .map(|_| ())
+            .map_err(move |e| warn!("s3 put failure; key={}, {}", key, e))
+    }
+
+    fn delete(&self, key: String) -> impl Future<Item = (), Error = ()> {
+        self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.clone(),
+                ..Default::default()
+            })
+            .map(|_| ())
+            .map_err(move |e| warn!("s3 delete failure; key={}, {}", key, e))
+    }
+
+    /// The body of an object, or `None` if there is no such key.
+    fn get(&self, key: String) -> impl Future<Item = Option<Vec<u8>>, Error = ()> {
+        let err_key = key.clone();
+
+        self.client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key,
+                ..Default::default()
+            })
+            .then(|res| match res {
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => Either::A(ok(None)),
+                Err(e) => Either::A(futures::future::err(format!("{}", e))),
+                Ok(output) => Either::B(match output.body {
+                    None => Either::A(ok(Some(vec![]))),
+                    Some(body) => Either::B(
+                        body.concat2()
+                            .map(|bytes| Some(bytes.to_vec()))
+                            .map_err(|e| format!("{}", e)),
+                    ),
+                }),
+            })
+            .map_err(move |e| warn!("s3 get failure; key={}, {}", err_key, e))
+    }
+
+    /// Every key under `prefix` with its last modified time, following continuation tokens.
+    fn list(
+        &self,
+        prefix: String,
+    ) -> impl Future<Item = Vec<(String, Option<DateTime<Utc>>)>, Error = ()> {
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+
+        futures::stream::unfold(Some(None), move |token: Option<Option<String>>| {
+            token.map(|continuation_token| {
+                client
+                    .list_objects_v2(ListObjectsV2Request {
+                        bucket: bucket.clone(),
+                        prefix: Some(prefix.clone()),
+                        continuation_token,
+                        ..Default::default()
+                    })
+                    .map(|output| {
+                        let next = match output.is_truncated {
+                            Some(true) => Some(output.next_continuation_token),
+                            _ => None,
+                        };
+                        let keys: Vec<_> = output
+                            .contents
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter_map(|o| {
+                                let modified = o
+                                    .last_modified
+                                    .and_then(|lm| DateTime::parse_from_rfc3339(&lm).ok())
+                                    .map(|lm| lm.with_timezone(&Utc));
+                                o.key.map(|k| (k, modified))
+                            })
+                            .collect();
+                        (keys, next)
+                    })
+            })
+        })
+        .concat2()
+        .map_err(|e| warn!("s3 list failure; {}", e))
+    }
+
+    /// Writes the index if it hasn't been written for `index_interval`.
+    fn maybe_write_index(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        {
+            let mut last = self.last_index.lock();
+            if last.elapsed() < self.index_interval {
+                return Box::new(ok(()));
+            }
+            *last = Instant::now();
+        }
+
+        let this = self.clone();
+        let snapshot = Utc::now();
+
+        Box::new(self.store.all().and_then(move |switches| {
+            match serde_json::to_vec(&Index { snapshot, switches }) {
+                Ok(json) => Either::A(this.put(this.index_key(), json)),
+                Err(e) => {
+                    warn!("failed to serialize s3 index; {}", e);
+                    Either::B(ok(()))
+                }
+            }
+        }))
+    }
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(key: &str, body: &[u8]) -> Option<T> {
+    match serde_json::from_slice(body) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!("failed to deserialize s3 object; key={}, err={}", key, e);
+            None
+        }
+    }
+}
+
+impl<S: 'static + Clone + Store + Send + Sync> Store for S3Store<S> {
+    fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        info!("Loading data from s3://{}/{}", self.bucket, self.prefix);
+
+        let this = self.clone();
+        let index_key = self.index_key();
+
+        let index = self.get(index_key.clone()).map(move |res| {
+            match res.and_then(|body| deserialize::<Index>(&index_key, &body)) {
+                None => (HashMap::new(), None),
+                Some(index) => {
+                    let by_name = index
+                        .switches
+                        .into_iter()
+                        .map(|s| (s.name.clone(), s))
+                        .collect::<HashMap<_, _>>();
+                    (by_name, Some(index.snapshot))
+                }
+            }
+        });
+
+        let f = index.join(self.list(self.switches_prefix())).and_then(
+            move |((mut indexed, snapshot), objects)| {
+                let prefix = this.switches_prefix();
+                let store = this.store.clone();
+
+                // The objects are the source of truth; the index only saves fetching the ones
+                // that haven't changed since it was written.
+                let loads = objects.into_iter().map(move |(key, modified)| {
+                    let name = key
+                        .trim_start_matches(prefix.as_str())
+                        .trim_end_matches(".json")
+                        .to_owned();
+
+                    let fresh = match (modified, snapshot) {
+                        (Some(m), Some(snapshot)) => m < snapshot,
+                        _ => false,
+                    };
+
+                    match indexed.remove(&name) {
+                        Some(s) if fresh => Either::A(ok(Some(s))),
+                        _ => Either::B(
+                            this.get(key.clone())
+                                .map(move |res| res.and_then(|body| deserialize(&key, &body))),
+                        ),
+                    }
+                });
+
+                futures::stream::futures_unordered(loads)
+                    .filter_map(|s| s)
+                    .map(move |s| store.insert(s))
+                    .buffer_unordered(16)
+                    .collect()
+                    .map(|_| ())
+            },
+        );
+
+        Box::new(f)
+    }
+
+    fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        self.store.all()
+    }
+
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        self.store.get(name)
+    }
+
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        let this = self.clone();
+        let index = self.clone();
+
+        let batches = self
+            .store
+            .expired(when, batch)
+            .chunks(batch)
+            .and_then(move |switches| {
+                let deletes: Vec<_> = switches
+                    .iter()
+                    .map(|s| this.delete(this.switch_key(&s.name)))
+                    .collect();
+
+                futures::future::join_all(deletes).map(|_| switches)
+            });
+
+        // Once the last batch has been deleted.
+        let indexed = futures::stream::once(Ok(()))
+            .and_then(move |_| index.maybe_write_index())
+            .filter_map(|_| None);
+
+        Box::new(
+            batches
+                .chain(indexed)
+                .map(futures::stream::iter_ok)
+                .flatten(),
+        )
+    }
+
+    fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
+        let json = match serde_json::to_vec(&s) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("failed to serialize switch; err={}, switch={:?}", e, s);
+                return Box::new(futures::future::err(()));
+            }
+        };
+
+        let put = self.put(self.switch_key(&s.name), json);
+
+        Box::new(self.store.insert(s).and_then(|_| put))
+    }
+
+    fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        let delete = self.delete(self.switch_key(name));
+
+        Box::new(self.store.take(name).and_then(|s| delete.map(move |_| s)))
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let this = self.clone();
+        let key = self.switch_key(name);
+
+        Box::new(self.store.update(name, f).and_then(move |(old, new)| {
+            let write = match (&old, &new) {
+                (_, Some(s)) => match serde_json::to_vec(s) {
+                    Ok(json) => Either::A(Either::A(this.put(key, json))),
+                    Err(e) => {
+                        warn!("failed to serialize switch; err={}, switch={:?}", e, s);
+                        Either::B(futures::future::err(()))
+                    }
+                },
+                (Some(_), None) => Either::A(Either::B(this.delete(key))),
+                (None, None) => Either::B(ok(())),
+            };
+
+            write.map(move |_| (old, new))
+        }))
+    }
+
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let bucket = self.bucket.clone();
+
+        Box::new(
+            self.client
+                .head_bucket(HeadBucketRequest {
+                    bucket: self.bucket.clone(),
+                })
+                .map_err(move |e| warn!("s3 head bucket failure; bucket={}, {}", bucket, e)),
+        )
+    }
+
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        self.store.next_due()
+    }
+}
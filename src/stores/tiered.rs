@@ -0,0 +1,200 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use log::{error, info, warn};
+use parking_lot::Mutex;
+
+use crate::stores::{MemoryStore, Store, Updater};
+use crate::Switch;
+
+/// TieredStore serves everything from memory and writes changes through to the backing store in
+/// the background, so check-ins don't wait on the network. Writes are applied one at a time in the
+/// order they were made. Changes not yet written are lost if the process dies before a `flush()`.
+#[derive(Debug, Clone)]
+pub struct TieredStore<S: Store> {
+    memory: MemoryStore,
+    backing: Arc<S>,
+    writes: UnboundedSender<Write>,
+    /// Taken by `init()` which starts the writer.
+    pending: Arc<Mutex<Option<UnboundedReceiver<Write>>>>,
+}
+
+#[derive(Debug)]
+enum Write {
+    Insert(Box<Switch>),
+    Take(String),
+    Expire(DateTime<Utc>, usize),
+    /// Answered once the writes before it are done.
+    Flush(oneshot::Sender<()>),
+}
+
+impl<S: 'static + Store + Send + Sync> TieredStore<S> {
+    pub fn new(backing: S) -> Self {
+        let (writes, pending) = unbounded();
+
+        Self {
+            memory: MemoryStore::new(),
+            backing: Arc::new(backing),
+            writes,
+            pending: Arc::new(Mutex::new(Some(pending))),
+        }
+    }
+
+    fn write(&self, write: Write) {
+        send(&self.writes, write)
+    }
+
+    fn writer(&self) -> impl Future<Item = (), Error = ()> {
+        let backing = Arc::clone(&self.backing);
+        let pending = self.pending.lock().take();
+
+        futures::stream::iter_ok::<_, ()>(pending)
+            .flatten()
+            .for_each(move |write| {
+                let f = match write {
+                    Write::Insert(s) => backing.insert(*s),
+                    Write::Take(name) => Box::new(backing.take(&name).map(|_| ())),
+                    Write::Expire(when, batch) => {
+                        Box::new(backing.expired(when, batch).for_each(|_| Ok(())))
+                    }
+                    Write::Flush(done) => {
+                        let _ = done.send(());
+                        Box::new(futures::future::ok(()))
+                    }
+                };
+
+                // A failed write has already been logged by the backing store and shouldn't stop
+                // the ones after it.
+                f.then(|_| Ok(()))
+            })
+    }
+}
+
+fn send(writes: &UnboundedSender<Write>, write: Write) {
+    if let Err(e) = writes.unbounded_send(write) {
+        warn!(
+            "write-behind writer has stopped; dropped {:?}",
+            e.into_inner()
+        );
+    }
+}
+
+impl<S: 'static + Store + Send + Sync> Store for TieredStore<S> {
+    fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let memory = self.memory.clone();
+        let writer = self.writer();
+        let all_backing = Arc::clone(&self.backing);
+
+        let f = self
+            .backing
+            .init()
+            .and_then(move |_| all_backing.all())
+            .and_then(move |switches| {
+                info!("loaded {} switches from the backing store", switches.len());
+
+                futures::stream::iter_ok(switches).for_each(move |s| memory.insert(s))
+            })
+            .then(move |res| match res {
+                Ok(()) => {
+                    tokio::spawn(writer);
+                    Ok(())
+                }
+                // Dropping the writer closes the queue, so that changes made meanwhile are
+                // refused with a warning rather than kept in memory forever.
+                Err(()) => {
+                    error!("write-behind writer not started as the backing store failed to load");
+                    drop(writer);
+                    Err(())
+                }
+            });
+
+        Box::new(f)
+    }
+
+    fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        self.memory.all()
+    }
+
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        self.memory.get(name)
+    }
+
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        self.write(Write::Expire(when, batch));
+        self.memory.expired(when, batch)
+    }
+
+    /// The switch is only written to the backing store once the memory has taken it, as one
+    /// refused there isn't stored at all.
+    fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
+        let writes = self.writes.clone();
+        let write = Write::Insert(Box::new(s.clone()));
+
+        Box::new(self.memory.insert(s).map(move |_| send(&writes, write)))
+    }
+
+    fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        self.write(Write::Take(name.to_owned()));
+        self.memory.take(name)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let writes = self.writes.clone();
+        let name = name.to_owned();
+
+        Box::new(self.memory.update(&name, f).map(move |(old, new)| {
+            match (&old, &new) {
+                (_, Some(s)) => send(&writes, Write::Insert(Box::new(s.clone()))),
+                (Some(_), None) => send(&writes, Write::Take(name)),
+                (None, None) => {}
+            }
+            (old, new)
+        }))
+    }
+
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        self.backing.health()
+    }
+
+    fn lock_watcher(
+        &self,
+        holder: &str,
+        ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        self.backing.lock_watcher(holder, ttl)
+    }
+
+    /// The backing store is written every switch soon after the memory is, so it can tell when
+    /// they are due.
+    fn wakeups(&self) -> Option<Box<Stream<Item = DateTime<Utc>, Error = ()> + Send>> {
+        self.backing.wakeups()
+    }
+
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        self.memory.next_due()
+    }
+
+    fn flush(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        let (done, flushed) = oneshot::channel();
+        self.write(Write::Flush(done));
+
+        let backing = Arc::clone(&self.backing);
+        Box::new(
+            flushed
+                .map_err(|_| warn!("write-behind writer stopped before flushing"))
+                .and_then(move |_| backing.flush()),
+        )
+    }
+}
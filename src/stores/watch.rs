@@ -0,0 +1,195 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use futures::{Future, Stream};
+use log::info;
+use parking_lot::Mutex;
+
+use crate::stores::{Store, Updater};
+use crate::Switch;
+
+/// How many changes a watcher may fall behind by before it is dropped.
+const WATCHER_BACKLOG: usize = 1024;
+
+/// A change made to a switch through a `WatchedStore`.
+#[derive(Debug, Clone)]
+pub enum Change {
+    /// The switch as it is now, whether new or changed.
+    Updated(Box<Switch>),
+    /// The name of a switch that has been removed.
+    Removed(String),
+}
+
+/// The earliest a switch inserted since the watcher last woke is due, and the way to wake it.
+#[derive(Debug, Default)]
+struct Due {
+    earliest: Option<DateTime<Utc>>,
+    wake: Option<Sender<()>>,
+}
+
+impl Due {
+    /// Wakes the watcher for `s`. Wakeups coalesce into the earliest, so none is lost to a full
+    /// channel.
+    fn inserted(&mut self, s: &Switch) {
+        // Due once the second it wakes in is over, as `expired()` goes by whole seconds.
+        let at = Utc.timestamp(s.wakes_at().timestamp() + 1, 0);
+        self.earliest = Some(self.earliest.map_or(at, |earliest| earliest.min(at)));
+
+        if let Some(wake) = &mut self.wake {
+            if wake.try_send(()).is_err_and(|e| e.is_disconnected()) {
+                self.wake = None;
+            }
+        }
+    }
+}
+
+/// WatchedStore tells its watchers of every change made through it. Only changes made by this
+/// instance are seen, not those made to a shared backing store by others.
+#[derive(Debug, Clone)]
+pub struct WatchedStore<S: Store> {
+    store: S,
+    watchers: Arc<Mutex<Vec<Sender<Change>>>>,
+    due: Arc<Mutex<Due>>,
+}
+
+impl<S: Store> WatchedStore<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            watchers: Arc::new(Mutex::new(vec![])),
+            due: Arc::new(Mutex::new(Due::default())),
+        }
+    }
+
+    /// The changes from now on. A watcher that doesn't keep up is dropped, ending the stream, so
+    /// that it can't hold up the others or use up memory.
+    pub fn watch(&self) -> Receiver<Change> {
+        let (tx, rx) = channel(WATCHER_BACKLOG);
+        self.watchers.lock().push(tx);
+        rx
+    }
+}
+
+fn publish(watchers: &Mutex<Vec<Sender<Change>>>, change: Change) {
+    let mut watchers = watchers.lock();
+    let all: Vec<_> = watchers.drain(..).collect();
+
+    for mut tx in all {
+        match tx.try_send(change.clone()) {
+            Ok(()) => watchers.push(tx),
+            Err(ref e) if e.is_full() => info!("dropping watcher that fell behind"),
+            // It went away.
+            Err(_) => {}
+        }
+    }
+}
+
+impl<S: Store> Store for WatchedStore<S> {
+    fn init(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        self.store.init()
+    }
+
+    fn insert(&self, s: Switch) -> Box<Future<Item = (), Error = ()> + Send> {
+        let watchers = Arc::clone(&self.watchers);
+        let due = Arc::clone(&self.due);
+        let change = Change::Updated(Box::new(s.clone()));
+
+        Box::new(self.store.insert(s.clone()).map(move |_| {
+            due.lock().inserted(&s);
+            publish(&watchers, change)
+        }))
+    }
+
+    fn expired(
+        &self,
+        when: DateTime<Utc>,
+        batch: usize,
+    ) -> Box<Stream<Item = Switch, Error = ()> + Send> {
+        let watchers = Arc::clone(&self.watchers);
+
+        Box::new(self.store.expired(when, batch).inspect(move |s| {
+            publish(&watchers, Change::Removed(s.name.clone()));
+        }))
+    }
+
+    fn take(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        let watchers = Arc::clone(&self.watchers);
+
+        Box::new(self.store.take(name).inspect(move |s| {
+            if let Some(s) = s {
+                publish(&watchers, Change::Removed(s.name.clone()));
+            }
+        }))
+    }
+
+    fn all(&self) -> Box<Future<Item = Vec<Switch>, Error = ()> + Send> {
+        self.store.all()
+    }
+
+    fn get(&self, name: &str) -> Box<Future<Item = Option<Switch>, Error = ()> + Send> {
+        self.store.get(name)
+    }
+
+    fn update(
+        &self,
+        name: &str,
+        f: Updater,
+    ) -> Box<Future<Item = (Option<Switch>, Option<Switch>), Error = ()> + Send> {
+        let watchers = Arc::clone(&self.watchers);
+        let due = Arc::clone(&self.due);
+
+        Box::new(
+            self.store
+                .update(name, f)
+                .inspect(move |(old, new)| match (old, new) {
+                    (_, Some(s)) => {
+                        due.lock().inserted(s);
+                        publish(&watchers, Change::Updated(Box::new(s.clone())))
+                    }
+                    (Some(s), None) => publish(&watchers, Change::Removed(s.name.clone())),
+                    (None, None) => {}
+                }),
+        )
+    }
+
+    fn has_room(&self, name: &str) -> bool {
+        self.store.has_room(name)
+    }
+
+    fn health(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        self.store.health()
+    }
+
+    fn flush(&self) -> Box<Future<Item = (), Error = ()> + Send> {
+        self.store.flush()
+    }
+
+    fn lock_watcher(
+        &self,
+        holder: &str,
+        ttl: Duration,
+    ) -> Box<Future<Item = bool, Error = ()> + Send> {
+        self.store.lock_watcher(holder, ttl)
+    }
+
+    /// Switches inserted through this instance wake the watcher as well as any the store has, so
+    /// that it can sleep until the next is due without missing a sooner one.
+    fn wakeups(&self) -> Option<Box<Stream<Item = DateTime<Utc>, Error = ()> + Send>> {
+        let (wake, woken) = channel(1);
+        self.due.lock().wake = Some(wake);
+
+        let due = Arc::clone(&self.due);
+        let inserted = woken.filter_map(move |()| due.lock().earliest.take());
+
+        match self.store.wakeups() {
+            Some(wakeups) => Some(Box::new(inserted.select(wakeups))),
+            None => Some(Box::new(inserted)),
+        }
+    }
+
+    fn next_due(&self) -> Box<Future<Item = Option<DateTime<Utc>>, Error = ()> + Send> {
+        self.store.next_due()
+    }
+}
@@ -0,0 +1,85 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use log::warn;
+use tokio::timer::Interval;
+
+/// Tells systemd `state`, such as `READY=1`, when it started condemn as a `Type=notify` service.
+/// Otherwise there is no one to tell.
+pub fn notify(state: &str) {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    if let Err(e) = send(&socket, state) {
+        warn!("failed to notify systemd; state={}, {}", state, e);
+    }
+}
+
+/// Sends `state` to `socket`, a path or, starting with `@`, the name of an abstract socket.
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+
+    match socket.as_bytes() {
+        [b'@', name @ ..] => send_abstract(&datagram, name, state)?,
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(datagram: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    let addr = SocketAddr::from_abstract_name(name)?;
+    datagram.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Only Linux has abstract sockets, so only systemd there could have given one.
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &[u8], _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+/// How long systemd waits to be fed before it restarts condemn, if its watchdog is on for this
+/// process.
+pub fn watchdog() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+    // Set when the watchdog is for another process, such as the shell that started condemn.
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec)).filter(|timeout| *timeout > Duration::from_secs(0))
+}
+
+/// Feeds the watchdog twice every `timeout` for as long as `alive` says condemn is, so that
+/// systemd restarts it once it isn't or the runtime stops running this at all.
+pub fn feed<A: Fn() -> bool>(timeout: Duration, alive: A) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(timeout / 2)
+        .map_err(|e| warn!("systemd watchdog failed; {}", e))
+        .for_each(move |_| {
+            if alive() {
+                notify("WATCHDOG=1");
+            }
+            Ok(())
+        })
+}
@@ -0,0 +1,75 @@
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use serde_derive::Deserialize;
+use warp::{filters, Filter, Rejection};
+
+use crate::Switch;
+
+/// The secret a request presents for its switch, and whether to make one up for a switch it
+/// creates without one. Only a hash of a switch's token is kept, so it is never shown again.
+#[derive(Debug, Clone)]
+pub struct Token {
+    presented: Option<String>,
+    generate: bool,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// How a switch's token is stored.
+pub fn hash(token: &str) -> String {
+    hex::encode(sha256(token.as_bytes()))
+}
+
+impl Token {
+    /// Presents `token` as well if the request didn't already, such as from a request body.
+    pub fn or(self, token: Option<String>) -> Self {
+        Token {
+            presented: self.presented.or(token),
+            ..self
+        }
+    }
+
+    /// Whether the request may change `s`. A switch without a token can be changed by anyone.
+    pub fn allows(&self, s: &Switch) -> bool {
+        match (&s.token_hash, &self.presented) {
+            (None, _) => true,
+            (Some(expected), Some(presented)) => {
+                let presented = hash(presented);
+                presented.len() == expected.len()
+                    && openssl::memcmp::eq(presented.as_bytes(), expected.as_bytes())
+            }
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Whether the request came with a token.
+    pub fn is_presented(&self) -> bool {
+        self.presented.is_some()
+    }
+
+    /// The token for a switch the request creates; the one it presented or, if tokens are
+    /// generated, a new one.
+    pub fn for_new(&self) -> Option<String> {
+        if self.presented.is_some() || !self.generate {
+            return self.presented.clone();
+        }
+
+        let mut bytes = [0; 16];
+        rand_bytes(&mut bytes).expect("the system has randomness");
+        Some(hex::encode(bytes))
+    }
+}
+
+/// The token from the `X-Switch-Token` header or, failing that, the `token` query parameter.
+/// With `generate` switches created without one are given one.
+pub fn token(generate: bool) -> impl Filter<Extract = (Token,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("x-switch-token")
+        .and(filters::query::query())
+        .map(move |header: Option<String>, query: TokenQuery| Token {
+            presented: header.or(query.token),
+            generate,
+        })
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+
+use futures::{Future, Stream};
+use log::{debug, info, warn};
+use openssl::rand::rand_bytes;
+use tokio::io::AsyncRead;
+use tokio::net::{TcpStream, UnixListener};
+use tokio::reactor::Handle;
+
+/// Certificates for TLS connections made by condemn.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// PEM encoded CA certificate to trust in addition to the system roots.
+    pub ca_file: Option<String>,
+    /// PKCS#12 archive holding a client certificate and key.
+    pub identity_file: Option<String>,
+    pub identity_password: String,
+}
+
+impl TlsConfig {
+    fn connector(&self) -> Result<native_tls::TlsConnector, String> {
+        let mut builder = native_tls::TlsConnector::builder();
+
+        if let Some(ca_file) = &self.ca_file {
+            let pem = fs::read(ca_file).map_err(|e| format!("{}; {}", ca_file, e))?;
+            let cert = native_tls::Certificate::from_pem(&pem)
+                .map_err(|e| format!("{}; {}", ca_file, e))?;
+            builder.add_root_certificate(cert);
+        }
+
+        if let Some(identity_file) = &self.identity_file {
+            let der = fs::read(identity_file).map_err(|e| format!("{}; {}", identity_file, e))?;
+            let identity = native_tls::Identity::from_pkcs12(&der, &self.identity_password)
+                .map_err(|e| format!("{}; {}", identity_file, e))?;
+            builder.identity(identity);
+        }
+
+        builder.build().map_err(|e| format!("{}", e))
+    }
+}
+
+/// The tunnels started so far by `host:port`, so that each is only started once however many
+/// times its URL is read.
+static TUNNELS: Mutex<BTreeMap<(String, u16), PathBuf>> = Mutex::new(BTreeMap::new());
+
+/// A directory only this user can enter, for the tunnels' sockets.
+fn socket_dir() -> Result<PathBuf, String> {
+    let mut bytes = [0u8; 8];
+    rand_bytes(&mut bytes).map_err(|e| format!("{}", e))?;
+
+    let dir = env::temp_dir().join(format!("condemn-{}", hex::encode(bytes)));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("{:?}; {}", dir, e))?;
+    Ok(dir)
+}
+
+/// Starts a tunnel for clients that can only speak plain TCP, or gives the one already started.
+/// Connections to the returned Unix socket, which only this user may use, are wrapped in TLS and
+/// forwarded to `host:port`, looked up again for each so that it follows a failover. The tunnel
+/// runs on its own thread for the life of the process.
+pub fn tls_tunnel(host: &str, port: u16, tls: &TlsConfig) -> Result<PathBuf, String> {
+    let mut tunnels = TUNNELS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(path) = tunnels.get(&(host.to_owned(), port)) {
+        return Ok(path.clone());
+    }
+
+    let connector = tokio_tls::TlsConnector::from(tls.connector()?);
+
+    let path = socket_dir()?.join("redis.sock");
+    let listener = StdUnixListener::bind(&path).map_err(|e| format!("{:?}; {}", path, e))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("{:?}; {}", path, e))?;
+
+    info!("tunneling {:?} to {}:{} over TLS", path, host, port);
+
+    let remote = host.to_owned();
+    thread::spawn(move || {
+        let listener = UnixListener::from_std(listener, &Handle::default())
+            .expect("tunnel listener should bind to the default reactor");
+
+        let server = listener
+            .incoming()
+            .map_err(|e| warn!("tunnel accept failure; {}", e))
+            .for_each(move |client| {
+                let connector = connector.clone();
+                let host = remote.clone();
+
+                let forward = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map_err(|e| format!("{}:{}; {}", host, port, e))
+                    .and_then(|mut addrs| {
+                        addrs
+                            .next()
+                            .ok_or_else(|| format!("{}:{}; no addresses", host, port))
+                    });
+
+                let forward = futures::future::result(forward)
+                    .and_then(|addr| TcpStream::connect(&addr).map_err(|e| format!("{}", e)))
+                    .and_then(move |tcp| {
+                        connector.connect(&host, tcp).map_err(|e| format!("{}", e))
+                    })
+                    .and_then(move |tls| {
+                        let (client_read, client_write) = client.split();
+                        let (server_read, server_write) = tls.split();
+
+                        tokio::io::copy(client_read, server_write)
+                            .join(tokio::io::copy(server_read, client_write))
+                            .map_err(|e| format!("{}", e))
+                    })
+                    .map(|_| debug!("tunnel connection closed"))
+                    .map_err(|e| warn!("tunnel failure; {}", e));
+
+                tokio::spawn(forward);
+                Ok(())
+            });
+
+        tokio::run(server);
+    });
+
+    tunnels.insert((host.to_owned(), port), path.clone());
+    Ok(path)
+}
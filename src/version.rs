@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::json;
+use warp::http::Response;
+
+/// What `GET /version` says about the build and how it is running.
+pub struct Build {
+    /// The `--store` kind.
+    pub store: String,
+    pub write_behind: bool,
+    /// The `--notify` kinds and the schemes of the notifier URLs, without their secrets.
+    pub notifiers: Vec<String>,
+    started_at: DateTime<Utc>,
+    started: Instant,
+}
+
+impl Build {
+    pub fn new(store: String, write_behind: bool, notifiers: Vec<String>) -> Self {
+        Self {
+            store,
+            write_behind,
+            notifiers,
+            started_at: Utc::now(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// The scheme of a notifier URL, such as `slack` for `slack://token@channel`.
+pub fn scheme(url: &str) -> String {
+    url.split("://").next().unwrap_or(url).to_owned()
+}
+
+/// Answers with the version, commit and build time of this condemn, what it was started with and
+/// for how long it has been up.
+pub fn handle(build: Arc<Build>) -> Response<String> {
+    let built_at = env!("CONDEMN_BUILT_AT")
+        .parse()
+        .ok()
+        .map(|secs| Utc.timestamp(secs, 0));
+
+    let body = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("CONDEMN_COMMIT"),
+        "built_at": built_at,
+        "store": build.store,
+        "write_behind": build.write_behind,
+        "notifiers": build.notifiers,
+        "started_at": build.started_at,
+        "uptime_seconds": build.started.elapsed().as_secs(),
+    });
+
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .unwrap()
+}
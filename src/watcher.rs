@@ -0,0 +1,541 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::{loop_fn, ok, Either, Loop};
+use futures::{Future, Stream};
+use log::{info, warn};
+use openssl::rand::rand_bytes;
+use parking_lot::Mutex;
+use tokio::timer::{Delay, Interval};
+
+use crate::config;
+use crate::metrics::Histogram;
+use crate::stores::Store;
+
+/// The longest the watcher may be told to wait between ticks, well short of `STALL_AFTER`.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the watcher waits between ticks when the store wakes it as switches are due, a tick
+/// only being needed in case a wakeup was lost.
+pub const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The longest the watcher backs off for while the store keeps failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Ticks in a row the store may fail before the watcher alerts that it is blind.
+pub const ALERT_AFTER: u64 = 5;
+
+/// How long the watcher may go without finishing a tick before it counts as stalled, well past
+/// the longest it waits between them.
+pub const STALL_AFTER: Duration = Duration::from_secs(180);
+
+/// How far the system clock may stray from the time that has passed between ticks before it
+/// counts as having jumped.
+const MAX_DRIFT: Duration = Duration::from_secs(30);
+
+/// A jump of the system clock between ticks, such as when a VM is restored from a snapshot or
+/// the time is set by hand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jump {
+    /// The clock jumped `by` ahead of the time that passed, which had by then reached `since`
+    /// by the clock as it was.
+    Forward {
+        since: DateTime<Utc>,
+        by: chrono::Duration,
+    },
+    /// The clock jumped this far back.
+    Backward(chrono::Duration),
+}
+
+/// The jump of the clock since the tick at `last`, if it strayed that far from the time that
+/// passed.
+fn jumped(last: (DateTime<Utc>, Instant), now: (DateTime<Utc>, Instant)) -> Option<Jump> {
+    let max = chrono::Duration::from_std(MAX_DRIFT).expect("the drift is small");
+    // Suspending the process or the machine for longer than this is taken as a jump as well.
+    let passed = chrono::Duration::from_std(now.1 - last.1).ok()?;
+    let drift = now.0.signed_duration_since(last.0) - passed;
+
+    if drift > max {
+        Some(Jump::Forward {
+            since: last.0 + passed,
+            by: drift,
+        })
+    } else if -drift > max {
+        Some(Jump::Backward(-drift))
+    } else {
+        None
+    }
+}
+
+/// How long the watcher waits between ticks, `--watch-interval` or that of a `--config` file,
+/// kept so that it can be loaded again while the watcher runs.
+pub struct TickInterval {
+    default: Duration,
+    config: Option<String>,
+    millis: AtomicU64,
+}
+
+impl TickInterval {
+    pub fn new(default: Duration, config: Option<String>) -> Result<Self, String> {
+        let interval = TickInterval {
+            default,
+            config,
+            millis: AtomicU64::new(0),
+        };
+        interval.reload()?;
+        Ok(interval)
+    }
+
+    /// Reads the `--config` file again and waits its interval between ticks from the next on.
+    /// If it can't be read the interval there was is kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let options = config::read(self.config.as_deref())?;
+        let interval = match options.value_of("watch-interval") {
+            Some(interval) => humantime::parse_duration(interval).map_err(|e| format!("{}", e))?,
+            None => self.default,
+        };
+
+        self.millis
+            .store(interval.as_millis() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn get(&self) -> Duration {
+        Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
+
+/// How the watcher is doing, for `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct Health {
+    /// Ticks in a row the store failed, since it last didn't.
+    failures: AtomicU64,
+    failures_total: AtomicU64,
+    clock_jumps_total: AtomicU64,
+    /// When a tick last finished, as a Unix timestamp. 0 until the first has.
+    last_tick: AtomicU64,
+    tick_duration: Mutex<Histogram>,
+    examined_total: AtomicU64,
+    expired_total: AtomicU64,
+    /// From the deadlines of switches that missed to the watcher notifying of them.
+    dispatch_latency: Mutex<Histogram>,
+}
+
+impl Health {
+    /// Counts a switch the store gave as due, whatever came of it.
+    pub fn examined(&self) {
+        self.examined_total.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Counts a switch that missed, being notified of `latency` after its deadline. Those that
+    /// missed while condemn was offline have none worth telling.
+    pub fn expired(&self, latency: Option<chrono::Duration>) {
+        self.expired_total.fetch_add(1, Ordering::SeqCst);
+        if let Some(latency) = latency.and_then(|l| l.to_std().ok()) {
+            self.dispatch_latency.lock().observe(latency.as_secs_f64());
+        }
+    }
+    /// Whether the watcher has stalled, having not finished a tick for `after`, such as
+    /// `STALL_AFTER`. Before the first it is measured from `since`.
+    pub fn stalled(&self, since: DateTime<Utc>, after: Duration) -> bool {
+        let last = match self.last_tick.load(Ordering::SeqCst) {
+            0 => since.timestamp(),
+            last => last as i64,
+        };
+
+        Utc::now().timestamp() - last > after.as_secs() as i64
+    }
+
+    /// Counts how a tick went, giving the ticks in a row that have now failed.
+    fn record(&self, ok: bool, interval: Duration) -> u64 {
+        if ok {
+            let failures = self.failures.swap(0, Ordering::SeqCst);
+            if failures > 0 {
+                info!("watcher recovered; failures={}", failures);
+            }
+            return 0;
+        }
+
+        self.failures_total.fetch_add(1, Ordering::SeqCst);
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        warn!(
+            "watcher failed to check the store, backing off; failures={}, backoff={:?}",
+            failures,
+            backoff(failures, interval)
+        );
+        failures
+    }
+
+    /// In the Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        out.push_str("# HELP condemn_watcher_tick_duration_seconds How long the watcher took to check the store.\n");
+        out.push_str("# TYPE condemn_watcher_tick_duration_seconds histogram\n");
+        self.tick_duration
+            .lock()
+            .render(out, "condemn_watcher_tick_duration_seconds", "");
+
+        out.push_str("# HELP condemn_watcher_switches_examined_total Switches the store gave the watcher as due.\n");
+        out.push_str("# TYPE condemn_watcher_switches_examined_total counter\n");
+        let _ = writeln!(
+            out,
+            "condemn_watcher_switches_examined_total {}",
+            self.examined_total.load(Ordering::SeqCst)
+        );
+
+        out.push_str("# HELP condemn_watcher_switches_expired_total Switches the watcher found had missed.\n");
+        out.push_str("# TYPE condemn_watcher_switches_expired_total counter\n");
+        let _ = writeln!(
+            out,
+            "condemn_watcher_switches_expired_total {}",
+            self.expired_total.load(Ordering::SeqCst)
+        );
+
+        out.push_str("# HELP condemn_watcher_dispatch_latency_seconds From the deadline of a switch that missed to the watcher notifying of it.\n");
+        out.push_str("# TYPE condemn_watcher_dispatch_latency_seconds histogram\n");
+        self.dispatch_latency
+            .lock()
+            .render(out, "condemn_watcher_dispatch_latency_seconds", "");
+
+        out.push_str("# HELP condemn_watcher_last_tick_timestamp_seconds When the watcher last finished checking the store.\n");
+        out.push_str("# TYPE condemn_watcher_last_tick_timestamp_seconds gauge\n");
+        let _ = writeln!(
+            out,
+            "condemn_watcher_last_tick_timestamp_seconds {}",
+            self.last_tick.load(Ordering::SeqCst)
+        );
+
+        out.push_str("# HELP condemn_watcher_consecutive_failures Watcher ticks in a row the store failed.\n");
+        out.push_str("# TYPE condemn_watcher_consecutive_failures gauge\n");
+        let _ = writeln!(
+            out,
+            "condemn_watcher_consecutive_failures {}",
+            self.failures.load(Ordering::SeqCst)
+        );
+
+        out.push_str("# HELP condemn_watcher_failures_total Watcher ticks the store failed.\n");
+        out.push_str("# TYPE condemn_watcher_failures_total counter\n");
+        let _ = writeln!(
+            out,
+            "condemn_watcher_failures_total {}",
+            self.failures_total.load(Ordering::SeqCst)
+        );
+
+        out.push_str("# HELP condemn_watcher_clock_jumps_total Jumps of the system clock between watcher ticks.\n");
+        out.push_str("# TYPE condemn_watcher_clock_jumps_total counter\n");
+        let _ = writeln!(
+            out,
+            "condemn_watcher_clock_jumps_total {}",
+            self.clock_jumps_total.load(Ordering::SeqCst)
+        );
+    }
+}
+
+/// The lock replicas sharing a store take turns with so that only one of them watches, the others
+/// taking over within `ttl` of it going away.
+pub struct Lock {
+    holder: String,
+    ttl: Duration,
+    held: AtomicBool,
+}
+
+impl Lock {
+    /// How often the holder must take the lock again to keep it.
+    pub fn renew_every(&self) -> Duration {
+        self.ttl / 3
+    }
+
+    pub fn new(ttl: Duration) -> Self {
+        let mut bytes = [0u8; 8];
+        rand_bytes(&mut bytes).expect("the system has randomness");
+
+        Lock {
+            holder: hex::encode(bytes),
+            ttl,
+            held: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes or keeps the lock in `store`, resolving to whether this replica is to watch. Without
+    /// a lock it always is.
+    pub fn take<S: Store>(
+        lock: Option<&Arc<Lock>>,
+        store: &S,
+    ) -> impl Future<Item = bool, Error = ()> {
+        let lock = match lock {
+            Some(lock) => Arc::clone(lock),
+            None => return Either::A(ok(true)),
+        };
+
+        Either::B(store.lock_watcher(&lock.holder, lock.ttl).map(move |held| {
+            match (lock.held.swap(held, Ordering::SeqCst), held) {
+                (false, true) => info!("took the watcher lock; holder={}", lock.holder),
+                (true, false) => warn!("lost the watcher lock; holder={}", lock.holder),
+                _ => (),
+            }
+            held
+        }))
+    }
+}
+
+/// How long to wait after `failures` ticks in a row have failed, doubling from the interval with
+/// each.
+fn backoff(failures: u64, interval: Duration) -> Duration {
+    interval
+        .checked_mul(1 << failures.min(16))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+/// `delay` give or take a tenth at random, so that replicas sharing a store don't query it in
+/// lockstep.
+fn jittered(delay: Duration) -> Duration {
+    let mut bytes = [0u8; 2];
+    rand_bytes(&mut bytes).expect("the system has randomness");
+    let r = f64::from(u16::from_le_bytes(bytes)) / f64::from(u16::MAX);
+
+    delay.mul_f64(0.9 + r / 5.0)
+}
+
+/// Wakes the watcher as switches are due, from `Store::wakeups()`, each saying one is due by then.
+pub type Wakeups = Box<Stream<Item = DateTime<Utc>, Error = ()> + Send>;
+
+/// When `at` is by the monotonic clock, or now if it has passed.
+fn instant(at: DateTime<Utc>) -> Instant {
+    Instant::now()
+        + at.signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default()
+}
+
+/// How long to sleep for when `due` is when the store next has a switch to give, at least the
+/// interval so that stores that can't tell are polled as before, and at most `idle`.
+fn until_due(due: Option<DateTime<Utc>>, interval: Duration, idle: Duration) -> Duration {
+    let idle = jittered(idle);
+    match due {
+        Some(due) => instant(due)
+            .saturating_duration_since(Instant::now())
+            .clamp(interval.min(idle), idle),
+        None => idle,
+    }
+}
+
+/// Waits until `until`, or less if `wakeups` says a switch is due sooner. Resolves to `wakeups`
+/// unless it has ended.
+fn wait(
+    until: Instant,
+    wakeups: Option<Wakeups>,
+) -> impl Future<Item = Option<Wakeups>, Error = ()> {
+    loop_fn((until, wakeups), |(until, wakeups)| {
+        let delay = Delay::new(until).then(|_| Ok::<_, ()>(()));
+
+        let wakeups = match wakeups {
+            Some(wakeups) => wakeups,
+            None => return Either::A(delay.map(|_| Loop::Break(None))),
+        };
+
+        Either::B(
+            delay
+                .select2(wakeups.into_future())
+                .then(move |res| match res {
+                    Ok(Either::A((_, next))) | Err(Either::A((_, next))) => {
+                        Ok(Loop::Break(next.into_inner()))
+                    }
+                    // Sleeping on until a switch inserted for later is due.
+                    Ok(Either::B(((Some(due), wakeups), _))) => match instant(due) {
+                        due if due > Instant::now() => {
+                            Ok(Loop::Continue((until.min(due), Some(wakeups))))
+                        }
+                        _ => Ok(Loop::Break(Some(wakeups))),
+                    },
+                    Ok(Either::B(((None, _), _))) | Err(Either::B((((), _), _))) => {
+                        warn!("store stopped waking the watcher, polling instead");
+                        Ok(Loop::Break(None))
+                    }
+                }),
+        )
+    })
+}
+
+/// Runs `tick` for ever, waiting the interval between ticks and backing off while they fail.
+/// With `wakeups` it instead sleeps until `next_due` says a switch is, for at most `idle`, unless
+/// woken sooner. `tick` is told of any jump of the clock since the last one, and `alert` once the
+/// store has failed `ALERT_AFTER` ticks in a row.
+pub fn run<F, T, N, D, A>(
+    health: Arc<Health>,
+    interval: Arc<TickInterval>,
+    wakeups: Option<Wakeups>,
+    idle: Duration,
+    tick: F,
+    next_due: N,
+    alert: A,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: FnMut(Option<Jump>) -> T,
+    T: Future<Item = (), Error = ()>,
+    N: Fn() -> D,
+    D: Future<Item = Option<DateTime<Utc>>, Error = ()>,
+    A: Fn(u64),
+{
+    let state = (tick, next_due, alert, wakeups, None);
+    loop_fn(state, move |(mut tick, next_due, alert, wakeups, last)| {
+        let health = Arc::clone(&health);
+        let interval = interval.get();
+
+        let now = (Utc::now(), Instant::now());
+        let jump = last.and_then(|last| jumped(last, now));
+        match jump {
+            Some(Jump::Forward { by, .. }) => warn!(
+                "SYSTEM CLOCK JUMPED FORWARD, not notifying for deadlines it skipped; by={}s",
+                by.num_seconds()
+            ),
+            Some(Jump::Backward(by)) => warn!(
+                "SYSTEM CLOCK JUMPED BACK, moving deadlines back with it; by={}s",
+                by.num_seconds()
+            ),
+            None => (),
+        }
+        if jump.is_some() {
+            health.clock_jumps_total.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let started = Instant::now();
+        tick(jump).then(move |res| {
+            health
+                .tick_duration
+                .lock()
+                .observe(started.elapsed().as_secs_f64());
+            health
+                .last_tick
+                .store(Utc::now().timestamp().max(1) as u64, Ordering::SeqCst);
+            let failures = health.record(res.is_ok(), interval);
+            if failures == ALERT_AFTER {
+                alert(failures);
+            }
+
+            // Backing off goes on regardless of wakeups, as ticking sooner would only fail again.
+            let waited = match (failures, wakeups) {
+                // A store that can't say when is taken to have a switch due now, and polled.
+                (0, Some(wakeups)) => Either::A(next_due().then(move |due| {
+                    let due = due.unwrap_or_else(|()| Some(Utc::now()));
+                    wait(
+                        Instant::now() + until_due(due, interval, idle),
+                        Some(wakeups),
+                    )
+                })),
+                // Not failing, the backoff is just the interval. It is never longer than `idle`
+                // either, so that ticks come as often as the lock and the watchdog need.
+                (failures, wakeups) => Either::B(
+                    wait(
+                        Instant::now() + jittered(backoff(failures, interval).min(idle)),
+                        None,
+                    )
+                    .map(move |_| wakeups),
+                ),
+            };
+            waited.map(move |waited| Loop::Continue((tick, next_due, alert, waited, Some(now))))
+        })
+    })
+}
+
+/// Checks every few seconds that the watcher is still ticking, such as that a tick isn't stuck
+/// waiting on a store that never answers. `alert` is told once it has stalled, and it is logged
+/// once it ticks again.
+pub fn monitor<A: Fn()>(health: Arc<Health>, alert: A) -> impl Future<Item = (), Error = ()> {
+    let started = Utc::now();
+    let alerted = AtomicBool::new(false);
+
+    Interval::new_interval(Duration::from_secs(10))
+        .map_err(|e| warn!("watcher monitor failed; {}", e))
+        .for_each(move |_| {
+            let stalled = health.stalled(started, STALL_AFTER);
+            match (alerted.swap(stalled, Ordering::SeqCst), stalled) {
+                (false, true) => alert(),
+                (true, false) => info!("watcher is ticking again"),
+                _ => (),
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(t: &str, instant: Instant) -> (DateTime<Utc>, Instant) {
+        (t.parse().unwrap(), instant)
+    }
+
+    #[test]
+    fn no_jump_while_the_clock_keeps_time() {
+        let start = Instant::now();
+        let last = tick("2026-10-14T10:00:00Z", start);
+
+        assert_eq!(
+            jumped(
+                last,
+                tick("2026-10-14T10:00:01Z", start + Duration::from_secs(1))
+            ),
+            None
+        );
+        // A little drift is only the clock being adjusted.
+        assert_eq!(
+            jumped(
+                last,
+                tick("2026-10-14T10:00:30Z", start + Duration::from_secs(1))
+            ),
+            None
+        );
+        assert_eq!(
+            jumped(
+                last,
+                tick("2026-10-14T09:59:40Z", start + Duration::from_secs(1))
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn jumps_forward() {
+        let start = Instant::now();
+        let last = tick("2026-10-14T10:00:00Z", start);
+        let now = tick("2026-10-14T11:00:10Z", start + Duration::from_secs(10));
+
+        assert_eq!(
+            jumped(last, now),
+            Some(Jump::Forward {
+                since: "2026-10-14T10:00:10Z".parse().unwrap(),
+                by: chrono::Duration::hours(1),
+            })
+        );
+    }
+
+    #[test]
+    fn jumps_backward() {
+        let start = Instant::now();
+        let last = tick("2026-10-14T10:00:00Z", start);
+        let now = tick("2026-10-14T09:00:10Z", start + Duration::from_secs(10));
+
+        assert_eq!(
+            jumped(last, now),
+            Some(Jump::Backward(chrono::Duration::hours(1)))
+        );
+    }
+
+    #[test]
+    fn suspending_counts_as_a_jump_forward() {
+        // The monotonic clock doesn't count the time the machine was asleep.
+        let start = Instant::now();
+        let last = tick("2026-10-14T10:00:00Z", start);
+        let now = tick("2026-10-14T18:00:00Z", start + Duration::from_secs(1));
+
+        assert_eq!(
+            jumped(last, now),
+            Some(Jump::Forward {
+                since: "2026-10-14T10:00:01Z".parse().unwrap(),
+                by: chrono::Duration::hours(8) - chrono::Duration::seconds(1),
+            })
+        );
+    }
+}